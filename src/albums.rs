@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, HashSet};
+use serde::{Deserialize, Serialize};
+use crate::favorites::Favorites;
+use crate::search::{SearchDoc, SearchIndex};
+use crate::types::topic::{MediaEntry, MediaUid, OwnedTopicId, TopicInfo, TopicListing};
+use crate::utils::read_topic;
+
+const SEP: char = '\0';
+
+/// Which media of the owner's topics belong in a smart album. Every field
+/// that is set must match, `topics` matches any of the listed topics.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AlbumQuery {
+    /// Topics or single media must be tagged with all of these
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    pub year: Option<i32>,
+    pub camera: Option<String>,
+    pub media_type: Option<String>,
+    /// Only media the owner rated at least this many stars
+    pub min_rating: Option<u8>,
+    /// Only media the owner favorited
+    #[serde(default)]
+    pub favorites: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmartAlbum {
+    pub name: String,
+    pub owner: String,
+    pub query: AlbumQuery,
+}
+
+/// Saved smart albums keyed by `{owner}\0{album name}`
+#[derive(Clone)]
+pub struct SmartAlbums {
+    albums: sled::Tree,
+}
+
+fn album_key(owner: &str, name: &str) -> Vec<u8> {
+    format!("{owner}{SEP}{name}").into_bytes()
+}
+
+impl AlbumQuery {
+    fn matches(&self, doc: &SearchDoc) -> bool {
+        let f = &doc.facets;
+        self.tags.iter().all(|t| f.has_tag(t))
+            && (self.topics.is_empty() || doc.topic.as_ref().is_some_and(|t| self.topics.contains(t)))
+            && self.year.is_none_or(|y| f.year == Some(y))
+            && self.camera.as_ref().is_none_or(|c| f.camera.as_ref() == Some(c))
+            && self.media_type.as_ref().is_none_or(|m| f.media_type.as_ref() == Some(m))
+    }
+}
+
+impl SmartAlbums {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            albums: db.open_tree("smart_albums")?,
+        })
+    }
+
+    pub fn get(&self, owner: &str, name: &str) -> anyhow::Result<Option<SmartAlbum>> {
+        self.albums.get(album_key(owner, name))?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    pub fn save(&self, album: &SmartAlbum) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(album)?;
+        self.albums.insert(album_key(&album.owner, &album.name), bytes)?;
+        Ok(())
+    }
+
+    /// Returns whether the album existed
+    pub fn remove(&self, owner: &str, name: &str) -> anyhow::Result<bool> {
+        Ok(self.albums.remove(album_key(owner, name))?.is_some())
+    }
+
+    pub fn list(&self, owner: &str) -> anyhow::Result<Vec<SmartAlbum>> {
+        self.albums.scan_prefix(format!("{owner}{SEP}").as_bytes())
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+}
+
+/// Evaluate the album query against the owner's topics. Media are merged in
+/// topic name order, keeping the order within each topic, and media found in
+/// several topics only show up once.
+pub fn evaluate(
+    album: &SmartAlbum,
+    search: &SearchIndex,
+    favorites: &Favorites,
+    topic_db: &sled::Tree,
+) -> anyhow::Result<TopicListing> {
+    let query = &album.query;
+    let mut by_topic: BTreeMap<String, HashSet<MediaUid>> = BTreeMap::new();
+    for doc in search.media_docs(&album.owner)? {
+        if !query.matches(&doc) {
+            continue;
+        }
+        if let Some(topic) = doc.topic {
+            by_topic.entry(topic).or_default().insert(doc.name);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut media = vec![];
+    for (topic, uids) in by_topic {
+        let topic_id = OwnedTopicId {
+            topic,
+            owner_id: album.owner.clone(),
+        }.to_string()?;
+        let Some(td) = read_topic(topic_db, &topic_id)? else { continue };
+
+        let marks = if query.favorites || query.min_rating.is_some() {
+            Some(favorites.marks_by(&topic_id, &album.owner)?)
+        } else {
+            None
+        };
+        let mut captions = td.captions();
+
+        for uid in td.list() {
+            if !uids.contains(&uid) || seen.contains(&uid) {
+                continue;
+            }
+            if let Some(ref marks) = marks {
+                let Some(mark) = marks.get(&uid) else { continue };
+                if (query.favorites && !mark.favorite)
+                    || query.min_rating.is_some_and(|n| mark.stars.unwrap_or(0) < n)
+                {
+                    continue;
+                }
+            }
+            seen.insert(uid.clone());
+            media.push(MediaEntry {
+                caption: captions.remove(&uid),
+                uid,
+            });
+        }
+    }
+
+    Ok(TopicListing {
+        info: TopicInfo {
+            title: Some(album.name.clone()),
+            ..Default::default()
+        },
+        media,
+    })
+}
@@ -0,0 +1,515 @@
+//! Stateless request signing for API clients that don't keep a cookie.
+//!
+//! A signed request carries
+//!
+//! ```text
+//! Authorization: Signature {public key}:{unix time}:{signature}
+//! X-Content-Hash: {hex blake3 of the body}
+//! ```
+//!
+//! where the key and signature are base64 and the signature is the ed25519
+//! signature of `{METHOD}\n{origin}\n{path and query}\n{unix time}\n{content hash}`.
+//! The hash header can be left out for requests without a body. A signature
+//! is accepted once, within `MAX_CLOCK_SKEW_SECS` of the server's clock.
+//!
+//! Browsers instead sign a challenge from `/generate-challenge` once and keep
+//! a cookie session, see `challenge_message`.
+
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{AUTHORIZATION, CONTENT_LENGTH},
+    middleware::Next,
+    web, FromRequest, HttpMessage, HttpRequest,
+};
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use crate::types::{Args, PublicKey, ServerErr, ServerState};
+use crate::trash::DAY_SECS;
+
+pub const CONTENT_HASH_HEADER: &str = "x-content-hash";
+/// How far a request's timestamp may be from the server's clock
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+/// How long a challenge can be signed after it was issued
+pub const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+/// Failed attempts from one address before it has to wait out the window
+pub const MAX_FAILED_ATTEMPTS: u32 = 10;
+pub const ATTEMPT_WINDOW_SECS: i64 = 15 * 60;
+/// Purpose of a challenge signed to sign in
+pub const AUTHENTICATE_PURPOSE: &str = "authenticate";
+const SCHEME: &str = "Signature ";
+const CHALLENGE_KEY: &str = "auth_challenge";
+
+/// Key that signed the current request, set by `verify_signed_request`
+#[derive(Clone)]
+struct SignedKey(String);
+
+/// Who sent the request, proven either by signing the request or with a
+/// verified cookie session that hasn't been revoked
+#[derive(Default)]
+pub struct Caller {
+    identity: Option<String>,
+    device: Option<String>,
+    session_id: Option<String>,
+}
+
+impl Caller {
+    /// Identity the caller owns data as
+    pub fn key(&self) -> Option<String> {
+        self.identity.clone()
+    }
+
+    /// Key the caller signed with, differs from `key` once rotated
+    pub fn device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+
+    /// Cookie session the caller signed in with
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+}
+
+#[cfg(test)]
+impl Caller {
+    /// Caller signed in with its identity key
+    pub fn signed_in(key: &str) -> Self {
+        Self {
+            identity: Some(key.to_string()),
+            device: Some(key.to_string()),
+            session_id: None,
+        }
+    }
+}
+
+fn caller(req: &HttpRequest) -> Result<Caller, ServerErr> {
+    let data = req.app_data::<web::Data<ServerState>>()
+        .ok_or_else(|| ServerErr::CustomError(anyhow::anyhow!("Server state missing")))?;
+    let signed = req.extensions().get::<SignedKey>().map(|SignedKey(key)| key.clone());
+    let (device, session_id) = match signed {
+        Some(key) => (key, None),
+        None => {
+            let session = req.get_session();
+            match (session.get::<String>("verified_pubkey")?, session.get::<String>("session_id")?) {
+                (Some(key), Some(id)) => (key, Some(id)),
+                _ => return Ok(Caller::default()),
+            }
+        }
+    };
+
+    let Some(identity) = data.keys.identity_of(&device)? else {
+        return Ok(Caller::default());
+    };
+    // Cookie sessions must still be registered and within their lifetime
+    if let Some(id) = &session_id {
+        let lifetime = data.args.session.lifetime_days * DAY_SECS;
+        let current = data.keys.session(&identity, id)?
+            .is_some_and(|s| s.created + lifetime > chrono::Utc::now().timestamp());
+        if !current {
+            return Ok(Caller::default());
+        }
+    }
+    Ok(Caller {
+        identity: Some(identity),
+        device: Some(device),
+        session_id,
+    })
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(caller(req).map_err(|e| e.into()))
+    }
+}
+
+pub fn verify_signature(
+    public_key: &PublicKey,
+    msg: &[u8],
+    signature: &[u8],
+) -> Result<(), ServerErr> {
+    let public_key = VerifyingKey::from_bytes(&public_key.to_bytes())
+        .map_err(|_| ServerErr::bad_request("Invalid public key"))?;
+
+    let sig: [u8; 64] = signature.try_into()
+        .map_err(|_| ServerErr::bad_request("Invalid signature length"))?;
+    let signature = Signature::from_bytes(&sig);
+
+    public_key.verify(msg, &signature)
+        .map_err(|_| ServerErr::unauthenticated("Signature is invalid"))
+}
+
+/// Origin signatures are bound to, so one signed for another server can't be
+/// replayed against this one. It never comes from the request, whose Host
+/// header the client picks, dev servers without one use their first bind address.
+pub fn origin(args: &Args) -> String {
+    match &args.auth.origin {
+        Some(origin) => origin.trim_end_matches('/').to_string(),
+        None => {
+            let scheme = if args.tls.cert_path.is_some() { "https" } else { "http" };
+            format!("{}://{}", scheme, args.bind.first().map(|b| b.as_str()).unwrap_or("localhost"))
+        }
+    }
+}
+
+/// The origin has to be configured outside of dev, and be a bare origin
+pub fn check_settings(args: &Args) -> Result<(), String> {
+    if args.auth.max_signed_body_bytes == 0 {
+        return Err("auth.max_signed_body_bytes must be at least 1".to_string());
+    }
+    let Some(origin) = &args.auth.origin else {
+        return match args.dev {
+            true => Ok(()),
+            false => Err("auth.origin must be set to the server's public origin like https://img.example.com".to_string()),
+        };
+    };
+    let uri: actix_web::http::Uri = origin.trim_end_matches('/').parse()
+        .map_err(|_| format!("Invalid auth.origin {}", origin))?;
+    if uri.scheme().is_none() || uri.host().is_none() || uri.path() != "/" {
+        return Err(format!("auth.origin {} must be a scheme and host like https://img.example.com", origin));
+    }
+    Ok(())
+}
+
+/// Message a client signs for a request
+pub fn request_message(
+    method: &str,
+    origin: &str,
+    path: &str,
+    timestamp: u64,
+    content_hash: &str,
+) -> Vec<u8> {
+    format!("{method}\n{origin}\n{path}\n{timestamp}\n{content_hash}").into_bytes()
+}
+
+/// Message a client signs with a challenge, `purpose` keeps a signature made
+/// to log in from being used to claim a handle and the like
+pub fn challenge_message(origin: &str, purpose: &str, challenge: &[u8]) -> Vec<u8> {
+    format!(
+        "img challenge\norigin: {origin}\npurpose: {purpose}\nchallenge: {}",
+        base64::engine::general_purpose::STANDARD.encode(challenge),
+    ).into_bytes()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Challenge {
+    bytes: Vec<u8>,
+    issued: i64,
+}
+
+/// Store a fresh challenge in the session, replacing any unused one
+pub fn issue_challenge(session: &Session) -> Result<Vec<u8>, ServerErr> {
+    let bytes = rand::random::<[u8; 32]>().to_vec();
+    session.insert(CHALLENGE_KEY, Challenge {
+        bytes: bytes.clone(),
+        issued: chrono::Utc::now().timestamp(),
+    })?;
+    Ok(bytes)
+}
+
+/// Take the challenge out of the session so it can't be used again
+pub fn take_challenge(session: &Session) -> Result<Vec<u8>, ServerErr> {
+    let challenge = session.remove_as::<Challenge>(CHALLENGE_KEY)
+        .and_then(|c| c.ok())
+        .ok_or_else(|| ServerErr::unauthenticated("No challenge found in session, request a new one"))?;
+    if chrono::Utc::now().timestamp() - challenge.issued > CHALLENGE_TTL_SECS {
+        return Err(ServerErr::unauthenticated("Challenge expired, request a new one"));
+    }
+    Ok(challenge.bytes)
+}
+
+/// Failed authentication attempts per client address in the current window.
+/// Kept in memory, a restart forgives everyone.
+#[derive(Clone, Default)]
+pub struct FailedAttempts {
+    attempts: Arc<Mutex<HashMap<IpAddr, (i64, u32)>>>,
+}
+
+impl FailedAttempts {
+    /// Run an authentication attempt from the address, refusing it while the
+    /// address is over its limit and counting and logging it if it fails
+    pub fn guard<T>(
+        &self,
+        peer: Option<SocketAddr>,
+        attempt: impl FnOnce() -> Result<T, ServerErr>,
+    ) -> Result<T, ServerErr> {
+        let Some(ip) = peer.map(|p| p.ip()) else {
+            return attempt();
+        };
+        let now = chrono::Utc::now().timestamp();
+
+        {
+            let attempts = self.attempts.lock().unwrap();
+            if let Some((start, count)) = attempts.get(&ip) {
+                if now - start < ATTEMPT_WINDOW_SECS && *count >= MAX_FAILED_ATTEMPTS {
+                    return Err(ServerErr::TooManyRequests(
+                        "Too many failed authentication attempts, try again later".to_string()));
+                }
+            }
+        }
+
+        let res = attempt();
+        if let Err(e) = &res {
+            log::warn!("Failed authentication from {}: {}", ip, e);
+            let mut attempts = self.attempts.lock().unwrap();
+            attempts.retain(|_, (start, _)| now - *start < ATTEMPT_WINDOW_SECS);
+            let (_, count) = attempts.entry(ip).or_insert((now, 0));
+            *count += 1;
+        }
+        res
+    }
+}
+
+/// Signatures accepted within the clock skew window, keyed by big endian
+/// timestamp then signature so expired ones are a prefix range.
+#[derive(Clone)]
+pub struct SeenSignatures {
+    seen: sled::Tree,
+}
+
+impl SeenSignatures {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            seen: db.open_tree("seen_signatures")?,
+        })
+    }
+
+    /// Record the signature, returning false if it was already used
+    pub fn insert(&self, timestamp: u64, signature: &[u8], now: u64) -> anyhow::Result<bool> {
+        let cutoff = now.saturating_sub(MAX_CLOCK_SKEW_SECS).to_be_bytes();
+        for key in self.seen.range(..cutoff.as_slice()).keys() {
+            self.seen.remove(key?)?;
+        }
+
+        let mut key = timestamp.to_be_bytes().to_vec();
+        key.extend_from_slice(signature);
+        Ok(self.seen.compare_and_swap(key, None as Option<&[u8]>, Some(&[]))?.is_ok())
+    }
+}
+
+/// Read the whole body of a signed request and check it hashes to the signed
+/// hash before any handler sees it, so a forged body changes nothing. Multipart
+/// parsing can stop at the closing boundary, so checking at the end of the
+/// stream isn't enough.
+async fn read_signed_body(
+    payload: &mut Payload,
+    expected: &str,
+    limit: u64,
+) -> Result<Bytes, ServerErr> {
+    let mut body = BytesMut::new();
+    let mut hasher = blake3::Hasher::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| ServerErr::bad_request(e.to_string()))?;
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(body_too_large(limit));
+        }
+        hasher.update(&chunk);
+        body.extend_from_slice(&chunk);
+    }
+    if hasher.finalize().to_hex().as_str() != expected {
+        return Err(ServerErr::unauthenticated("Body does not match the signed content hash"));
+    }
+    Ok(body.freeze())
+}
+
+fn body_too_large(limit: u64) -> ServerErr {
+    ServerErr::PayloadTooLarge(format!(
+        "Signed requests can be at most {} bytes, upload larger files with a session", limit))
+}
+
+/// A payload that yields an already buffered body
+fn buffered_payload(body: Bytes) -> Payload {
+    Payload::Stream { payload: Box::pin(futures_util::stream::once(async move { Ok(body) })) }
+}
+
+fn parse_authorization(value: &str) -> Result<(PublicKey, u64, Vec<u8>), ServerErr> {
+    let malformed = || ServerErr::bad_request("Authorization must be `Signature {key}:{time}:{signature}`");
+    let mut parts = value.strip_prefix(SCHEME).ok_or_else(malformed)?.trim().split(':');
+    let (Some(key), Some(timestamp), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(malformed());
+    };
+
+    let key = PublicKey::from_base64(key)
+        .ok_or_else(|| ServerErr::bad_request("Invalid public key"))?;
+    let timestamp = timestamp.parse().map_err(|_| malformed())?;
+    let signature = base64::engine::general_purpose::STANDARD.decode(signature)
+        .map_err(|_| ServerErr::bad_request("Signature is not base64"))?;
+    Ok((key, timestamp, signature))
+}
+
+/// Check the signature of requests with a `Signature` authorization and
+/// mark them as coming from the signing key. Other requests pass through.
+pub async fn verify_signed_request(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(value) = req.headers().get(AUTHORIZATION) else {
+        return next.call(req).await;
+    };
+    let value = value.to_str()
+        .map_err(|_| ServerErr::bad_request("Authorization header is not ascii"))?;
+    if !value.starts_with(SCHEME) {
+        return next.call(req).await;
+    }
+
+    let data = req.app_data::<web::Data<ServerState>>()
+        .ok_or_else(|| ServerErr::CustomError(anyhow::anyhow!("Server state missing")))?
+        .clone();
+    // Refuse what can't fit before spending the signature or buffering any of it
+    let limit = data.args.auth.max_signed_body_bytes;
+    let length = req.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return Err(body_too_large(limit).into());
+    }
+    let (key, content_hash) = data.auth_attempts.guard(req.peer_addr(), || {
+        let (key, timestamp, signature) = parse_authorization(value)?;
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW_SECS {
+            return Err(ServerErr::unauthenticated("Request timestamp is too far from the server time"));
+        }
+
+        let content_hash = match req.headers().get(CONTENT_HASH_HEADER) {
+            Some(hash) => hash.to_str()
+                .map_err(|_| ServerErr::bad_request("Content hash is not ascii"))?
+                .to_lowercase(),
+            None => blake3::hash(&[]).to_hex().to_string(),
+        };
+        let origin = origin(&data.args);
+        let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let msg = request_message(req.method().as_str(), &origin, path, timestamp, &content_hash);
+        verify_signature(&key, &msg, &signature)?;
+
+        if !data.signatures.insert(timestamp, &signature, now)? {
+            return Err(ServerErr::unauthenticated("Signature was already used"));
+        }
+        Ok((key, content_hash))
+    })?;
+
+    let body = read_signed_body(&mut req.take_payload(), &content_hash, limit).await?;
+    req.set_payload(buffered_payload(body));
+    req.extensions_mut().insert(SignedKey(key.to_string()));
+
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use super::*;
+
+    fn signer(seed: u8) -> (SigningKey, PublicKey) {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        let public = PublicKey::from_base64(&base64::engine::general_purpose::STANDARD.encode(key.verifying_key().to_bytes())).unwrap();
+        (key, public)
+    }
+
+    fn temp_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn signature_is_bound_to_message_and_key() {
+        let (key, public) = signer(1);
+        let (_, other) = signer(2);
+        let hash = blake3::hash(&[]).to_hex().to_string();
+        let msg = request_message("POST", "https://img.example.com", "/a/b", 100, &hash);
+        let sig = key.sign(&msg).to_bytes();
+
+        assert!(verify_signature(&public, &msg, &sig).is_ok());
+        assert!(verify_signature(&other, &msg, &sig).is_err());
+        let moved = request_message("POST", "https://img.example.com", "/a/c", 100, &hash);
+        assert!(verify_signature(&public, &moved, &sig).is_err());
+        let elsewhere = request_message("POST", "https://evil.example.com", "/a/b", 100, &hash);
+        assert!(verify_signature(&public, &elsewhere, &sig).is_err());
+        assert!(verify_signature(&public, &msg, &sig[..63]).is_err());
+    }
+
+    #[test]
+    fn challenge_signature_is_bound_to_purpose() {
+        let (key, public) = signer(1);
+        let msg = challenge_message("https://img.example.com", AUTHENTICATE_PURPOSE, b"challenge");
+        let sig = key.sign(&msg).to_bytes();
+
+        assert!(verify_signature(&public, &msg, &sig).is_ok());
+        for purpose in [crate::users::claim_purpose("x"), crate::keys::rotate_purpose("x")] {
+            let other = challenge_message("https://img.example.com", &purpose, b"challenge");
+            assert!(verify_signature(&public, &other, &sig).is_err());
+        }
+        // A signature made for another purpose doesn't sign in either
+        let claim = challenge_message("https://img.example.com", &crate::users::claim_purpose("x"), b"challenge");
+        let claim_sig = key.sign(&claim).to_bytes();
+        assert!(verify_signature(&public, &msg, &claim_sig).is_err());
+    }
+
+    #[test]
+    fn signatures_are_accepted_once() {
+        let seen = SeenSignatures::open(&temp_db()).unwrap();
+        assert!(seen.insert(1000, b"sig", 1000).unwrap());
+        assert!(!seen.insert(1000, b"sig", 1000).unwrap());
+        assert!(seen.insert(1000, b"other", 1000).unwrap());
+    }
+
+    #[test]
+    fn expired_signatures_are_forgotten() {
+        let seen = SeenSignatures::open(&temp_db()).unwrap();
+        assert!(seen.insert(1000, b"sig", 1000).unwrap());
+        let later = 1000 + MAX_CLOCK_SKEW_SECS + 1;
+        seen.insert(later, b"new", later).unwrap();
+        assert_eq!(seen.seen.len(), 1);
+    }
+
+    #[test]
+    fn origin_comes_from_settings() {
+        let mut args = Args {
+            dev: true,
+            bind: vec!["127.0.0.1:2500".to_string()],
+            ..Args::default()
+        };
+        assert_eq!(origin(&args), "http://127.0.0.1:2500");
+
+        args.auth.origin = Some("https://img.example.com/".to_string());
+        assert_eq!(origin(&args), "https://img.example.com");
+    }
+
+    #[test]
+    fn origin_is_required_outside_dev() {
+        let mut args = Args::default();
+        assert!(check_settings(&args).is_err());
+        args.dev = true;
+        assert!(check_settings(&args).is_ok());
+
+        args.dev = false;
+        args.auth.origin = Some("https://img.example.com".to_string());
+        assert!(check_settings(&args).is_ok());
+        args.auth.origin = Some("https://img.example.com/api".to_string());
+        assert!(check_settings(&args).is_err());
+        args.auth.origin = Some("img.example.com".to_string());
+        assert!(check_settings(&args).is_err());
+    }
+
+    #[actix_web::test]
+    async fn signed_body_must_match_its_hash() {
+        let body = Bytes::from_static(b"hello");
+        let hash = blake3::hash(&body).to_hex().to_string();
+
+        let read = read_signed_body(&mut buffered_payload(body.clone()), &hash, 100).await;
+        assert_eq!(read.unwrap(), body);
+        let forged = read_signed_body(&mut buffered_payload(Bytes::from_static(b"hellp")), &hash, 100).await;
+        assert!(matches!(forged, Err(ServerErr::Unauthenticated(_))));
+        let too_large = read_signed_body(&mut buffered_payload(body), &hash, 4).await;
+        assert!(matches!(too_large, Err(ServerErr::PayloadTooLarge(_))));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::types::topic::MediaUid;
+use crate::utils::{move_prefix, remove_prefix};
+
+const SEP: char = '\0';
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+pub const MAX_COMMENT_LEN: usize = 4000;
+pub const MAX_EMOJI_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Comment {
+    pub id: u64,
+    /// The comment this is a reply to
+    pub parent: Option<u64>,
+    /// Public key of the author
+    pub author: String,
+    pub body: String,
+    pub created: i64,
+    pub edited: Option<i64>,
+    /// Deleted comments keep their place in a thread with an empty body
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Serialize)]
+pub struct CommentPage {
+    pub comments: Vec<Comment>,
+    /// Pass as `after` to get the next page
+    pub next: Option<u64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ReactionCount {
+    pub count: usize,
+    /// Whether the requesting key left this reaction
+    pub reacted: bool,
+}
+
+/// Comments and reactions on media, scoped to the topic they were made in.
+/// Comments are keyed by `{topic id}\0{media}\0{comment id}` with big endian
+/// ids from sled so that a prefix scan returns them in posting order.
+/// Reactions are keyed by `{topic id}\0{media}\0{emoji}\0{author}`.
+#[derive(Clone)]
+pub struct Comments {
+    db: sled::Db,
+    comments: sled::Tree,
+    reactions: sled::Tree,
+}
+
+fn media_prefix(topic_id: &str, media: &MediaUid) -> Vec<u8> {
+    format!("{topic_id}{SEP}{media}{SEP}").into_bytes()
+}
+
+fn comment_key(topic_id: &str, media: &MediaUid, id: u64) -> Vec<u8> {
+    let mut key = media_prefix(topic_id, media);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+impl Comments {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            db: db.clone(),
+            comments: db.open_tree("comments")?,
+            reactions: db.open_tree("reactions")?,
+        })
+    }
+
+    pub fn get(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        id: u64,
+    ) -> anyhow::Result<Option<Comment>> {
+        self.comments.get(comment_key(topic_id, media, id))?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    fn put(&self, topic_id: &str, media: &MediaUid, comment: &Comment) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(comment)?;
+        self.comments.insert(comment_key(topic_id, media, comment.id), bytes)?;
+        Ok(())
+    }
+
+    pub fn add(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        author: String,
+        body: String,
+        parent: Option<u64>,
+    ) -> anyhow::Result<Comment> {
+        if let Some(parent) = parent {
+            self.get(topic_id, media, parent)?
+                .ok_or_else(|| anyhow::anyhow!("Parent comment {} does not exist", parent))?;
+        }
+
+        let comment = Comment {
+            id: self.db.generate_id()?,
+            parent,
+            author,
+            body,
+            created: chrono::Utc::now().timestamp(),
+            edited: None,
+            deleted: false,
+        };
+        self.put(topic_id, media, &comment)?;
+
+        Ok(comment)
+    }
+
+    pub fn edit(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        mut comment: Comment,
+        body: String,
+    ) -> anyhow::Result<Comment> {
+        comment.body = body;
+        comment.edited = Some(chrono::Utc::now().timestamp());
+        self.put(topic_id, media, &comment)?;
+        Ok(comment)
+    }
+
+    pub fn delete(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        mut comment: Comment,
+    ) -> anyhow::Result<()> {
+        comment.body = String::new();
+        comment.deleted = true;
+        self.put(topic_id, media, &comment)
+    }
+
+    /// Move the comments and reactions of a topic to another topic
+    pub fn move_topic(&self, from_id: &str, to_id: &str) -> anyhow::Result<()> {
+        let from = format!("{from_id}{SEP}");
+        let to = format!("{to_id}{SEP}");
+        move_prefix(&self.comments, from.as_bytes(), to.as_bytes(), false)?;
+        move_prefix(&self.reactions, from.as_bytes(), to.as_bytes(), false)?;
+        Ok(())
+    }
+
+    /// Remove the comments and reactions of a whole topic or of one media in it
+    pub fn purge(&self, topic_id: &str, media: Option<&MediaUid>) -> anyhow::Result<()> {
+        let prefix = match media {
+            Some(media) => media_prefix(topic_id, media),
+            None => format!("{topic_id}{SEP}").into_bytes(),
+        };
+        remove_prefix(&self.comments, &prefix)?;
+        remove_prefix(&self.reactions, &prefix)?;
+        Ok(())
+    }
+
+    /// A page of comments in posting order, starting after the given comment id
+    pub fn list(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        after: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<CommentPage> {
+        let prefix = media_prefix(topic_id, media);
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let start = match after {
+            Some(id) => comment_key(topic_id, media, id.saturating_add(1)),
+            None => prefix.clone(),
+        };
+
+        let mut comments = vec![];
+        for entry in self.comments.range(start..) {
+            let (key, bytes) = entry?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if comments.len() == limit {
+                let next = comments.last().map(|c: &Comment| c.id);
+                return Ok(CommentPage { comments, next });
+            }
+            comments.push(serde_json::from_slice(&bytes)?);
+        }
+
+        Ok(CommentPage { comments, next: None })
+    }
+
+    /// Add the reaction if the author hasn't left it yet, otherwise remove it.
+    /// Returns whether the reaction is now set.
+    pub fn toggle_reaction(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        emoji: &str,
+        author: &str,
+    ) -> anyhow::Result<bool> {
+        let mut key = media_prefix(topic_id, media);
+        key.extend_from_slice(format!("{emoji}{SEP}{author}").as_bytes());
+
+        if self.reactions.remove(&key)?.is_some() {
+            Ok(false)
+        } else {
+            self.reactions.insert(key, &[])?;
+            Ok(true)
+        }
+    }
+
+    pub fn reactions(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        viewer: Option<&str>,
+    ) -> anyhow::Result<BTreeMap<String, ReactionCount>> {
+        let prefix = media_prefix(topic_id, media);
+        let mut acc: BTreeMap<String, ReactionCount> = BTreeMap::new();
+
+        for entry in self.reactions.scan_prefix(&prefix) {
+            let (key, _) = entry?;
+            let rest = std::str::from_utf8(&key[prefix.len()..])?;
+            let Some((emoji, author)) = rest.split_once(SEP) else { continue };
+
+            let count = acc.entry(emoji.to_string()).or_default();
+            count.count += 1;
+            count.reacted |= viewer == Some(author);
+        }
+
+        Ok(acc)
+    }
+}
@@ -0,0 +1,325 @@
+//! Server settings come from, each overriding the one before
+//!
+//! 1. the defaults of `Args`
+//! 2. a TOML file, `--config` or `img.toml` in the working directory
+//! 3. `IMG_*` environment variables named after the setting, with `__`
+//!    between a section and its key like `IMG_SESSION__LIFETIME_DAYS=7`.
+//!    Lists are comma separated.
+//! 4. command line flags
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+use crate::types::{Args, SameSitePolicy};
+
+const DEFAULT_CONFIG: &str = "img.toml";
+const ENV_PREFIX: &str = "IMG_";
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sangha")]
+pub struct Cli {
+    /// TOML config file, defaults to img.toml if it exists
+    #[structopt(short, long, env = "IMG_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[structopt(short, long)]
+    pub root_dir: Option<PathBuf>,
+    /// Listen on this port on every bind address
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+    /// Address to listen on as `host:port`, can be given more than once
+    #[structopt(long)]
+    pub bind: Vec<String>,
+    #[structopt(short, long)]
+    pub migrate: bool,
+    #[structopt(short, long)]
+    pub db_path: Option<PathBuf>,
+    #[structopt(long)]
+    pub log_level: Option<String>,
+    #[structopt(long)]
+    pub trash_retention_days: Option<i64>,
+    #[structopt(long)]
+    pub dev: bool,
+    #[structopt(long)]
+    pub max_thumbnail_size: Option<u32>,
+    #[structopt(long)]
+    pub origin: Option<String>,
+    #[structopt(long)]
+    pub session_key_path: Option<PathBuf>,
+    #[structopt(long)]
+    pub insecure_cookie: bool,
+    #[structopt(long)]
+    pub same_site: Option<SameSitePolicy>,
+    #[structopt(long)]
+    pub session_lifetime_days: Option<i64>,
+    #[structopt(long)]
+    pub server_sessions: bool,
+    #[structopt(long)]
+    pub ui_origin: Option<String>,
+    #[structopt(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+    #[structopt(long, use_delimiter = true)]
+    pub cors_methods: Vec<String>,
+    #[structopt(long)]
+    pub cors_credentials: bool,
+    #[structopt(long)]
+    pub cors_max_age: Option<usize>,
+    #[structopt(long)]
+    pub tls_cert: Option<PathBuf>,
+    #[structopt(long)]
+    pub tls_key: Option<PathBuf>,
+    #[structopt(long)]
+    pub max_file_bytes: Option<u64>,
+    #[structopt(long)]
+    pub max_request_bytes: Option<u64>,
+    #[structopt(long)]
+    pub max_signed_body_bytes: Option<u64>,
+    #[structopt(long)]
+    pub quota_bytes: Option<u64>,
+    #[structopt(long)]
+    pub min_free_bytes: Option<u64>,
+}
+
+/// Read the settings from every source and check them
+pub fn load() -> Result<Args, String> {
+    let cli = Cli::from_args();
+    let path = cli.config.clone()
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG)).filter(|p| p.exists()));
+    let file = match path {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Error reading config {}: {}", path.display(), e))?;
+            Some(text.parse()
+                .map_err(|e| format!("Error parsing config {}: {}", path.display(), e))?)
+        }
+        None => None,
+    };
+    resolve(cli, file, std::env::vars())
+}
+
+/// Layer the config file, environment variables and flags over the defaults
+fn resolve(
+    cli: Cli,
+    file: Option<toml::Table>,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<Args, String> {
+    let mut table = toml::Table::try_from(Args::default())
+        .map_err(|e| format!("Error serializing default settings: {}", e))?;
+    if let Some(file) = file {
+        merge(&mut table, file);
+    }
+
+    for (name, value) in env {
+        if let Some(key) = name.strip_prefix(ENV_PREFIX).filter(|k| *k != "CONFIG") {
+            set_env(&mut table, &name, key, &value)?;
+        }
+    }
+
+    let mut args: Args = table.try_into().map_err(|e| format!("Invalid settings: {}", e))?;
+    apply_cli(&mut args, cli);
+    validate(&args)?;
+    Ok(args)
+}
+
+/// Overwrite the values of `base` with those set in `other`, section by section
+fn merge(base: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(other)) => merge(base, other),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Set the setting an environment variable names, parsing the value as a
+/// TOML value of the setting's type
+fn set_env(table: &mut toml::Table, name: &str, key: &str, raw: &str) -> Result<(), String> {
+    let unknown = || format!("Unknown setting {}", name);
+    let path: Vec<String> = key.to_lowercase().split("__").map(|s| s.to_string()).collect();
+    let (last, sections) = path.split_last().ok_or_else(unknown)?;
+    let mut table = table;
+    for section in sections {
+        table = match table.get_mut(section) {
+            Some(toml::Value::Table(t)) => t,
+            _ => return Err(unknown()),
+        };
+    }
+
+    let parse = |raw: &str| -> toml::Value {
+        format!("v = {raw}").parse::<toml::Table>().ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+    };
+    let value = match table.get(last) {
+        Some(toml::Value::Array(_)) => toml::Value::Array(
+            raw.split(',').filter(|s| !s.is_empty()).map(|s| parse(s.trim())).collect()),
+        Some(toml::Value::String(_)) => toml::Value::String(raw.to_string()),
+        // Unset optional settings aren't in the defaults, top level ones are fine
+        Some(_) | None => parse(raw),
+    };
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+fn apply_cli(args: &mut Args, cli: Cli) {
+    if let Some(root_dir) = cli.root_dir {
+        args.root_dir = root_dir;
+    }
+    if !cli.bind.is_empty() {
+        args.bind = cli.bind;
+    }
+    if let Some(port) = cli.port {
+        for addr in &mut args.bind {
+            let host = addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr);
+            *addr = format!("{host}:{port}");
+        }
+    }
+    if let Some(db_path) = cli.db_path {
+        args.db_path = db_path;
+    }
+    if let Some(log_level) = cli.log_level {
+        args.log_level = log_level;
+    }
+    if let Some(days) = cli.trash_retention_days {
+        args.trash_retention_days = days;
+    }
+    if let Some(size) = cli.max_thumbnail_size {
+        args.thumbnails.max_size = size;
+    }
+    if cli.origin.is_some() {
+        args.auth.origin = cli.origin;
+    }
+    if cli.session_key_path.is_some() {
+        args.session.key_path = cli.session_key_path;
+    }
+    if let Some(same_site) = cli.same_site {
+        args.session.same_site = same_site;
+    }
+    if let Some(days) = cli.session_lifetime_days {
+        args.session.lifetime_days = days;
+    }
+    if cli.ui_origin.is_some() {
+        args.cors.ui_origin = cli.ui_origin;
+    }
+    if !cli.cors_origins.is_empty() {
+        args.cors.origins = cli.cors_origins;
+    }
+    if !cli.cors_methods.is_empty() {
+        args.cors.methods = cli.cors_methods;
+    }
+    if let Some(max_age) = cli.cors_max_age {
+        args.cors.max_age = max_age;
+    }
+    if cli.tls_cert.is_some() {
+        args.tls.cert_path = cli.tls_cert;
+    }
+    if cli.tls_key.is_some() {
+        args.tls.key_path = cli.tls_key;
+    }
+    if let Some(bytes) = cli.max_file_bytes {
+        args.uploads.max_file_bytes = bytes;
+    }
+    if let Some(bytes) = cli.max_request_bytes {
+        args.uploads.max_request_bytes = bytes;
+    }
+    if let Some(bytes) = cli.max_signed_body_bytes {
+        args.auth.max_signed_body_bytes = bytes;
+    }
+    if let Some(bytes) = cli.quota_bytes {
+        args.uploads.quota_bytes = bytes;
+    }
+    if let Some(bytes) = cli.min_free_bytes {
+        args.uploads.min_free_bytes = bytes;
+    }
+    args.migrate |= cli.migrate;
+    args.dev |= cli.dev;
+    args.session.insecure_cookie |= cli.insecure_cookie;
+    args.session.server_store |= cli.server_sessions;
+    args.cors.credentials |= cli.cors_credentials;
+}
+
+fn validate(args: &Args) -> Result<(), String> {
+    if args.bind.is_empty() {
+        return Err("At least one bind address is needed".to_string());
+    }
+    for addr in &args.bind {
+        let port = addr.rsplit_once(':').map(|(_, port)| port);
+        if port.and_then(|p| p.parse::<u16>().ok()).is_none() {
+            return Err(format!("Bind address {} must be host:port", addr));
+        }
+    }
+    if args.trash_retention_days < 0 {
+        return Err("trash_retention_days can't be negative".to_string());
+    }
+    if args.thumbnails.max_size == 0 {
+        return Err("thumbnails.max_size must be at least 1".to_string());
+    }
+    if args.uploads.max_file_bytes == 0 || args.uploads.max_request_bytes == 0 {
+        return Err("uploads.max_file_bytes and uploads.max_request_bytes must be at least 1".to_string());
+    }
+    crate::auth::check_settings(args)?;
+    crate::session_store::check_settings(args)?;
+    crate::cors::check_settings(args)?;
+    crate::tls::check_settings(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(flags: &[&str]) -> Cli {
+        Cli::from_iter(std::iter::once("img").chain(flags.iter().copied()))
+    }
+
+    fn file(text: &str) -> Option<toml::Table> {
+        Some(text.parse().unwrap())
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let file = file("dev = true\n\
+            [uploads]\nmax_file_bytes = 1\nmax_request_bytes = 2\nquota_bytes = 3\n\
+            [session]\nlifetime_days = 9\n");
+        let env = env(&[
+            ("IMG_UPLOADS__MAX_REQUEST_BYTES", "20"),
+            ("IMG_UPLOADS__QUOTA_BYTES", "30"),
+            ("IMG_BIND", "127.0.0.1:1,127.0.0.1:2"),
+            ("HOME", "/root"),
+        ]);
+        let args = resolve(cli(&["--quota-bytes", "300"]), file, env).unwrap();
+
+        assert!(args.dev);
+        assert_eq!(args.session.lifetime_days, 9);
+        assert_eq!(args.uploads.max_file_bytes, 1);
+        assert_eq!(args.uploads.max_request_bytes, 20);
+        assert_eq!(args.uploads.quota_bytes, 300);
+        assert_eq!(args.bind, vec!["127.0.0.1:1", "127.0.0.1:2"]);
+        // Sections only set in part keep the defaults of the rest
+        assert_eq!(args.uploads.min_free_bytes, Args::default().uploads.min_free_bytes);
+        assert_eq!(args.session.same_site, Args::default().session.same_site);
+    }
+
+    #[test]
+    fn invalid_settings_are_refused() {
+        let dev = || file("dev = true");
+        assert!(resolve(cli(&[]), dev(), env(&[])).is_ok());
+        // The origin is only optional in dev
+        assert!(resolve(cli(&[]), None, env(&[])).is_err());
+
+        assert!(resolve(cli(&[]), file("dev = true\n[uploads]\nmax_bytes = 1"), env(&[])).is_err());
+        assert!(resolve(cli(&[]), dev(), env(&[("IMG_NOPE", "1")])).is_err());
+        assert!(resolve(cli(&[]), dev(), env(&[("IMG_NOPE__MAX", "1")])).is_err());
+        assert!(resolve(cli(&[]), dev(), env(&[("IMG_SESSION__LIFETIME_DAYS", "soon")])).is_err());
+
+        assert!(resolve(cli(&["--bind", "localhost"]), dev(), env(&[])).is_err());
+        assert!(resolve(cli(&["--max-file-bytes", "0"]), dev(), env(&[])).is_err());
+        assert!(resolve(cli(&["--max-signed-body-bytes", "0"]), dev(), env(&[])).is_err());
+        assert!(resolve(cli(&["--max-thumbnail-size", "0"]), dev(), env(&[])).is_err());
+        assert!(resolve(cli(&[]), dev(), env(&[("IMG_TRASH_RETENTION_DAYS", "-1")])).is_err());
+        assert!(resolve(cli(&["--origin", "img.example.com"]), None, env(&[])).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use actix_cors::Cors;
+use actix_web::http::{header, Method, Uri};
+use crate::auth::CONTENT_HASH_HEADER;
+use crate::types::Args;
+
+/// Origins allowed to make cross origin requests, the UI origin included
+fn origins(args: &Args) -> Vec<String> {
+    args.cors.origins.iter()
+        .chain(args.cors.ui_origin.iter())
+        .map(|origin| origin.trim_end_matches('/').to_string())
+        .collect()
+}
+
+fn credentials(args: &Args) -> bool {
+    args.cors.credentials || args.cors.ui_origin.is_some()
+}
+
+/// Cross origin policy of the server. With nothing configured only same
+/// origin requests work, the UI origin is allowed to send the session cookie.
+pub fn policy(args: &Args) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(args.cors.methods.iter().map(|m| m.as_str()))
+        .allowed_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::HeaderName::from_static(CONTENT_HASH_HEADER),
+        ])
+        .max_age(args.cors.max_age);
+    for origin in origins(args) {
+        cors = match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        };
+    }
+    if credentials(args) {
+        cors = cors.supports_credentials();
+    }
+    cors
+}
+
+/// Refuse CORS settings that are invalid or let any site act as a signed in user
+pub fn check_settings(args: &Args) -> Result<(), String> {
+    let origins = origins(args);
+    for origin in &origins {
+        if origin == "*" {
+            continue;
+        }
+        let uri: Uri = origin.parse().map_err(|_| format!("Invalid CORS origin {}", origin))?;
+        if uri.scheme().is_none() || uri.host().is_none() || uri.path() != "/" {
+            return Err(format!("CORS origin {} must be a scheme and host like https://example.com", origin));
+        }
+    }
+    if origins.iter().any(|o| o == "*") && credentials(args) {
+        return Err("A CORS origin of * can't be allowed credentials, list the origins instead".to_string());
+    }
+    for method in &args.cors.methods {
+        Method::from_bytes(method.as_bytes()).map_err(|_| format!("Invalid CORS method {}", method))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+
+/// Abort a sled transaction with an error
+pub fn abort(e: anyhow::Error) -> ConflictableTransactionError<anyhow::Error> {
+    ConflictableTransactionError::Abort(e)
+}
+
+/// The error that aborted a transaction, or the storage error it ran into
+pub fn tx_err(e: TransactionError<anyhow::Error>) -> anyhow::Error {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+pub fn to_string(bytes: sled::IVec) -> anyhow::Result<String> {
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
@@ -0,0 +1,177 @@
+use std::ops::Bound;
+use serde::{Deserialize, Serialize};
+use crate::types::topic::{MediaUid, TopicData};
+use crate::utils::preview_media;
+
+const SEP: char = '\0';
+const DEFAULT_LIMIT: usize = 50;
+/// Longest chain of renames followed when resolving a redirect
+const MAX_REDIRECT_DEPTH: usize = 8;
+pub const MAX_LIMIT: usize = 500;
+
+/// What a topic looks like in a listing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TopicSummary {
+    pub topic: String,
+    pub owner: String,
+    pub title: Option<String>,
+    /// Number of media in the topic
+    pub media: usize,
+    /// Cover or first image, served from /thumbnail/{cover}
+    pub cover: Option<MediaUid>,
+    /// Unix time of the last change, None for topics not changed since listings exist
+    pub modified: Option<i64>,
+    pub public: bool,
+}
+
+#[derive(Serialize)]
+pub struct TopicPage {
+    pub topics: Vec<TopicSummary>,
+    /// Pass as `after` to get the next page
+    pub next: Option<String>,
+}
+
+/// Topic summaries for listing without reading every topic. `owner_topics`
+/// is keyed by `{owner}\0{topic}` and `public_topics` by `{topic}\0{owner}`
+/// for public topics only. `redirects` maps `{owner}\0{topic}` of renamed or
+/// merged topics to the topic name they now live under.
+#[derive(Clone)]
+pub struct TopicDirectory {
+    owner_topics: sled::Tree,
+    public_topics: sled::Tree,
+    redirects: sled::Tree,
+}
+
+fn owner_key(owner: &str, topic: &str) -> String {
+    format!("{owner}{SEP}{topic}")
+}
+
+fn public_key(topic: &str, owner: &str) -> String {
+    format!("{topic}{SEP}{owner}")
+}
+
+impl TopicSummary {
+    pub fn new(owner: &str, td: &TopicData) -> Self {
+        Self {
+            topic: td.name.clone(),
+            owner: owner.to_string(),
+            title: td.info().title,
+            media: td.list().len(),
+            cover: preview_media(td),
+            modified: td.modified,
+            public: td.public,
+        }
+    }
+}
+
+/// Up to limit summaries under the prefix starting after the given key
+fn page(
+    tree: &sled::Tree,
+    prefix: &str,
+    after: Option<String>,
+    limit: Option<usize>,
+    cursor: impl Fn(&TopicSummary) -> String,
+) -> anyhow::Result<TopicPage> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let start = match after {
+        Some(key) => Bound::Excluded(key.into_bytes()),
+        None => Bound::Included(prefix.as_bytes().to_vec()),
+    };
+
+    let mut topics = vec![];
+    for entry in tree.range((start, Bound::Unbounded)) {
+        let (key, bytes) = entry?;
+        if !key.starts_with(prefix.as_bytes()) {
+            break;
+        }
+        if topics.len() == limit {
+            let next = topics.last().map(&cursor);
+            return Ok(TopicPage { topics, next });
+        }
+        topics.push(serde_json::from_slice(&bytes)?);
+    }
+
+    Ok(TopicPage { topics, next: None })
+}
+
+impl TopicDirectory {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            owner_topics: db.open_tree("owner_topics")?,
+            public_topics: db.open_tree("public_topics")?,
+            redirects: db.open_tree("topic_redirects")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owner_topics.is_empty()
+    }
+
+    pub fn update(&self, summary: &TopicSummary) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(summary)?;
+        let public = public_key(&summary.topic, &summary.owner);
+        let key = owner_key(&summary.owner, &summary.topic);
+        // A topic that exists again is no longer redirected
+        self.redirects.remove(&key)?;
+        self.owner_topics.insert(key, bytes.clone())?;
+        if summary.public {
+            self.public_topics.insert(public, bytes)?;
+        } else {
+            self.public_topics.remove(public)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, owner: &str, topic: &str) -> anyhow::Result<()> {
+        self.owner_topics.remove(owner_key(owner, topic))?;
+        self.public_topics.remove(public_key(topic, owner))?;
+        Ok(())
+    }
+
+    pub fn is_public(&self, owner: &str, topic: &str) -> anyhow::Result<bool> {
+        Ok(self.public_topics.contains_key(public_key(topic, owner))?)
+    }
+
+    pub fn set_redirect(&self, owner: &str, from: &str, to: &str) -> anyhow::Result<()> {
+        self.redirects.insert(owner_key(owner, from), to.as_bytes())?;
+        Ok(())
+    }
+
+    /// The topic a renamed or merged topic of the owner now lives under
+    pub fn redirect(&self, owner: &str, topic: &str) -> anyhow::Result<Option<String>> {
+        let mut current = None;
+        for _ in 0..MAX_REDIRECT_DEPTH {
+            let name = current.as_deref().unwrap_or(topic);
+            match self.redirects.get(owner_key(owner, name))? {
+                Some(to) => current = Some(String::from_utf8(to.to_vec())?),
+                None => break,
+            }
+        }
+        Ok(current)
+    }
+
+    /// Topics of an owner in name order, `after` is a topic name
+    pub fn owned_by(
+        &self,
+        owner: &str,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<TopicPage> {
+        let prefix = format!("{owner}{SEP}");
+        let after = after.map(|topic| owner_key(owner, &topic));
+        page(&self.owner_topics, &prefix, after, limit, |t| t.topic.clone())
+    }
+
+    /// Public topics of every owner by name, `after` is a `{topic}.{owner}` address
+    pub fn public(
+        &self,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<TopicPage> {
+        let after = after.map(|address| match address.split_once('.') {
+            Some((topic, owner)) => public_key(topic, owner),
+            None => public_key(&address, ""),
+        });
+        page(&self.public_topics, "", after, limit, |t| format!("{}.{}", t.topic, t.owner))
+    }
+}
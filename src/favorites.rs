@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::types::topic::MediaUid;
+use crate::utils::{move_prefix, remove_prefix};
+
+const SEP: char = '\0';
+pub const MAX_STARS: u8 = 5;
+
+/// What a single key thinks of a media item
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Mark {
+    pub favorite: bool,
+    /// 1 to 5 stars
+    pub stars: Option<u8>,
+}
+
+/// How all contributors marked a media item
+#[derive(Serialize, Default, Debug)]
+pub struct MarkSummary {
+    pub favorites: usize,
+    pub ratings: usize,
+    pub average_stars: Option<f32>,
+}
+
+/// Favorites and star ratings per public key on media, scoped to a topic.
+/// Keyed by `{topic id}\0{media}\0{pubkey}`, entries are removed once a key
+/// neither favorites nor rates the media.
+#[derive(Clone)]
+pub struct Favorites {
+    marks: sled::Tree,
+}
+
+fn topic_prefix(topic_id: &str) -> Vec<u8> {
+    format!("{topic_id}{SEP}").into_bytes()
+}
+
+fn mark_key(topic_id: &str, media: &MediaUid, pubkey: &str) -> Vec<u8> {
+    format!("{topic_id}{SEP}{media}{SEP}{pubkey}").into_bytes()
+}
+
+impl Favorites {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            marks: db.open_tree("favorites")?,
+        })
+    }
+
+    /// Apply a change to the mark of a key on a media item
+    fn update(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        pubkey: &str,
+        f: impl Fn(&mut Mark),
+    ) -> anyhow::Result<Mark> {
+        let key = mark_key(topic_id, media, pubkey);
+        let mut result = Mark::default();
+        self.marks.fetch_and_update(&key, |old| {
+            let mut mark: Mark = old
+                .and_then(|bytes| serde_json::from_slice(bytes).ok())
+                .unwrap_or_default();
+            f(&mut mark);
+            result = mark.clone();
+            (mark != Mark::default())
+                .then(|| serde_json::to_vec(&mark).expect("Mark always serializes"))
+        })?;
+        Ok(result)
+    }
+
+    pub fn set_favorite(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        pubkey: &str,
+        favorite: bool,
+    ) -> anyhow::Result<Mark> {
+        self.update(topic_id, media, pubkey, |m| m.favorite = favorite)
+    }
+
+    /// Zero stars clears the rating
+    pub fn set_stars(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        pubkey: &str,
+        stars: u8,
+    ) -> anyhow::Result<Mark> {
+        if stars > MAX_STARS {
+            return Err(anyhow::anyhow!("Rating must be between 0 and {}", MAX_STARS));
+        }
+        self.update(topic_id, media, pubkey, |m| m.stars = (stars > 0).then_some(stars))
+    }
+
+    /// Move the marks of a topic to another topic
+    pub fn move_topic(&self, from_id: &str, to_id: &str) -> anyhow::Result<()> {
+        move_prefix(&self.marks, &topic_prefix(from_id), &topic_prefix(to_id), false)?;
+        Ok(())
+    }
+
+    /// Remove the marks of a whole topic or of one media in it
+    pub fn purge(&self, topic_id: &str, media: Option<&MediaUid>) -> anyhow::Result<()> {
+        let mut prefix = topic_prefix(topic_id);
+        if let Some(media) = media {
+            prefix.extend_from_slice(format!("{media}{SEP}").as_bytes());
+        }
+        remove_prefix(&self.marks, &prefix)?;
+        Ok(())
+    }
+
+    /// Call f with the media, public key and mark of every mark in the topic
+    fn for_each(
+        &self,
+        topic_id: &str,
+        mut f: impl FnMut(MediaUid, &str, Mark),
+    ) -> anyhow::Result<()> {
+        let prefix = topic_prefix(topic_id);
+        for entry in self.marks.scan_prefix(&prefix) {
+            let (key, bytes) = entry?;
+            let rest = std::str::from_utf8(&key[prefix.len()..])?;
+            let Some((media, pubkey)) = rest.split_once(SEP) else { continue };
+            f(media.to_string(), pubkey, serde_json::from_slice(&bytes)?);
+        }
+        Ok(())
+    }
+
+    /// Marks left by one key on the media of a topic
+    pub fn marks_by(
+        &self,
+        topic_id: &str,
+        pubkey: &str,
+    ) -> anyhow::Result<BTreeMap<MediaUid, Mark>> {
+        let mut acc = BTreeMap::new();
+        self.for_each(topic_id, |media, author, mark| {
+            if author == pubkey {
+                acc.insert(media, mark);
+            }
+        })?;
+        Ok(acc)
+    }
+
+    /// Favorite counts and average rating of every marked media in a topic
+    pub fn summary(&self, topic_id: &str) -> anyhow::Result<BTreeMap<MediaUid, MarkSummary>> {
+        let mut acc: BTreeMap<MediaUid, (MarkSummary, u32)> = BTreeMap::new();
+        self.for_each(topic_id, |media, _, mark| {
+            let (summary, total_stars) = acc.entry(media).or_default();
+            if mark.favorite {
+                summary.favorites += 1;
+            }
+            if let Some(stars) = mark.stars {
+                summary.ratings += 1;
+                *total_stars += stars as u32;
+            }
+        })?;
+
+        Ok(acc.into_iter()
+            .map(|(media, (mut summary, total_stars))| {
+                if summary.ratings > 0 {
+                    summary.average_stars = Some(total_stars as f32 / summary.ratings as f32);
+                }
+                (media, summary)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+use crate::db::{abort, to_string, tx_err};
+
+const SEP: char = '\0';
+
+/// What a key registers about itself
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    /// Unix time of the first registration
+    pub registered: i64,
+}
+
+/// A key linked to an account
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceInfo {
+    pub key: String,
+    /// Unix time the key was linked, None for the key that made the account
+    pub added: Option<i64>,
+}
+
+/// A signed in cookie session
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Key that signed in
+    pub key: String,
+    /// Unix time of the sign in
+    pub created: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SessionList {
+    /// Session the request was made with
+    pub current: Option<String>,
+    pub sessions: Vec<SessionInfo>,
+}
+
+/// Device keys, the account identity they sign for, profiles and sessions.
+/// Data is owned by an identity, which is the first key the account was used
+/// with. A key that was never linked or rotated is its own identity and needs
+/// no entry in `identities`. `devices` lists the other keys of an account by
+/// `{identity}\0{key}`. `retired_keys` holds keys rotated away from or
+/// removed, they can't sign in again. `sessions` is keyed by
+/// `{identity}\0{session id}`.
+#[derive(Clone)]
+pub struct Keys {
+    identities: sled::Tree,
+    devices: sled::Tree,
+    retired: sled::Tree,
+    profiles: sled::Tree,
+    sessions: sled::Tree,
+}
+
+/// Purpose of a challenge signed by the old key to rotate to a new key
+pub fn rotate_purpose(new_key: &str) -> String {
+    format!("rotate-key:{new_key}")
+}
+
+/// Purpose of a challenge signed by a device of an account to link a new key
+pub fn link_purpose(new_key: &str) -> String {
+    format!("link-device:{new_key}")
+}
+
+fn session_key(identity: &str, id: &str) -> String {
+    format!("{identity}{SEP}{id}")
+}
+
+fn device_key(identity: &str, key: &str) -> String {
+    format!("{identity}{SEP}{key}")
+}
+
+impl Keys {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            identities: db.open_tree("key_identities")?,
+            devices: db.open_tree("account_devices")?,
+            retired: db.open_tree("retired_keys")?,
+            profiles: db.open_tree("profiles")?,
+            sessions: db.open_tree("sessions")?,
+        })
+    }
+
+    /// Identity the key signs for, None if the key was retired
+    pub fn identity_of(&self, key: &str) -> anyhow::Result<Option<String>> {
+        if self.retired.contains_key(key.as_bytes())? {
+            return Ok(None);
+        }
+        match self.identities.get(key.as_bytes())? {
+            Some(identity) => Ok(Some(to_string(identity)?)),
+            None => Ok(Some(key.to_string())),
+        }
+    }
+
+    /// Account a key belongs or belonged to, for addressing data by any key
+    pub fn account_of(&self, key: &str) -> anyhow::Result<String> {
+        match self.identities.get(key.as_bytes())?.or(self.retired.get(key.as_bytes())?) {
+            Some(identity) => to_string(identity),
+            None => Ok(key.to_string()),
+        }
+    }
+
+    /// Keys that can sign for the account
+    pub fn devices(&self, identity: &str) -> anyhow::Result<Vec<DeviceInfo>> {
+        let mut devices = vec![];
+        if !self.retired.contains_key(identity.as_bytes())? {
+            devices.push(DeviceInfo { key: identity.to_string(), added: None });
+        }
+        for bytes in self.devices.scan_prefix(format!("{identity}{SEP}").as_bytes()).values() {
+            devices.push(serde_json::from_slice(&bytes?)?);
+        }
+        Ok(devices)
+    }
+
+    /// Whether the key is an account of its own, with linked devices or a profile
+    pub fn is_account(&self, key: &str) -> anyhow::Result<bool> {
+        let has_devices = self.devices.scan_prefix(format!("{key}{SEP}").as_bytes()).next().is_some();
+        Ok(has_devices || self.profiles.contains_key(key.as_bytes())?)
+    }
+
+    /// Let a key that isn't used yet sign for the account
+    pub fn link(&self, identity: &str, new_key: &str) -> anyhow::Result<()> {
+        if self.is_account(new_key)? {
+            return Err(anyhow::anyhow!("Key already has an account of its own"));
+        }
+        let device = serde_json::to_vec(&DeviceInfo {
+            key: new_key.to_string(),
+            added: Some(chrono::Utc::now().timestamp()),
+        })?;
+        (&self.identities, &self.devices, &self.retired).transaction(|(identities, devices, retired)| {
+            if retired.get(new_key.as_bytes())?.is_some() {
+                return Err(abort(anyhow::anyhow!("Key was retired")));
+            }
+            if identities.get(new_key.as_bytes())?.is_some() {
+                return Err(abort(anyhow::anyhow!("Key already belongs to an account")));
+            }
+            identities.insert(new_key.as_bytes(), identity.as_bytes())?;
+            devices.insert(device_key(identity, new_key).as_bytes(), device.clone())?;
+            Ok(())
+        }).map_err(tx_err)?;
+
+        self.move_sessions(new_key, identity)
+    }
+
+    /// Retire a key of the account and revoke its sessions. The account's
+    /// last key can't be removed.
+    pub fn remove_device(&self, identity: &str, key: &str) -> anyhow::Result<()> {
+        if self.identity_of(key)?.as_deref() != Some(identity) {
+            return Err(anyhow::anyhow!("Key is not a device of the account"));
+        }
+        if self.devices(identity)?.len() < 2 {
+            return Err(anyhow::anyhow!("Can't remove the last device of an account"));
+        }
+        (&self.identities, &self.devices, &self.retired).transaction(|(identities, devices, retired)| {
+            identities.remove(key.as_bytes())?;
+            devices.remove(device_key(identity, key).as_bytes())?;
+            retired.insert(key.as_bytes(), identity.as_bytes())?;
+            Ok(())
+        }).map_err(tx_err)?;
+
+        self.remove_sessions(identity, Some(key))?;
+        Ok(())
+    }
+
+    pub fn profile(&self, identity: &str) -> anyhow::Result<Option<Profile>> {
+        self.profiles.get(identity.as_bytes())?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    /// Create or update the profile of an identity
+    pub fn register(&self, identity: &str, display_name: Option<String>) -> anyhow::Result<Profile> {
+        let profile = Profile {
+            display_name,
+            registered: self.profile(identity)?
+                .map(|p| p.registered)
+                .unwrap_or_else(|| chrono::Utc::now().timestamp()),
+        };
+        self.profiles.insert(identity.as_bytes(), serde_json::to_vec(&profile)?)?;
+        Ok(profile)
+    }
+
+    /// Make the new key sign for the old key's identity and retire the old
+    /// key along with its sessions, returning the identity
+    pub fn rotate(&self, old_key: &str, new_key: &str) -> anyhow::Result<String> {
+        if old_key == new_key {
+            return Err(anyhow::anyhow!("New key is the old key"));
+        }
+        let identity = self.identity_of(old_key)?
+            .ok_or_else(|| anyhow::anyhow!("Key was already rotated"))?;
+        if self.is_account(new_key)? {
+            return Err(anyhow::anyhow!("New key already has an account of its own"));
+        }
+        let device = serde_json::to_vec(&DeviceInfo {
+            key: new_key.to_string(),
+            added: Some(chrono::Utc::now().timestamp()),
+        })?;
+        (&self.identities, &self.devices, &self.retired).transaction(|(identities, devices, retired)| {
+            if retired.get(new_key.as_bytes())?.is_some() {
+                return Err(abort(anyhow::anyhow!("New key was retired")));
+            }
+            if identities.get(new_key.as_bytes())?.is_some() {
+                return Err(abort(anyhow::anyhow!("New key already signs for an identity")));
+            }
+            identities.remove(old_key.as_bytes())?;
+            devices.remove(device_key(&identity, old_key).as_bytes())?;
+            retired.insert(old_key.as_bytes(), identity.as_bytes())?;
+            identities.insert(new_key.as_bytes(), identity.as_bytes())?;
+            devices.insert(device_key(&identity, new_key).as_bytes(), device.clone())?;
+            Ok(())
+        }).map_err(tx_err)?;
+
+        self.remove_sessions(&identity, Some(old_key))?;
+        self.move_sessions(new_key, &identity)?;
+        Ok(identity)
+    }
+
+    /// Sessions a key opened as its own identity move to the account it joined
+    fn move_sessions(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        for session in self.sessions(from)? {
+            self.sessions.remove(session_key(from, &session.id))?;
+            self.add_session(to, &session)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_session(&self, identity: &str, session: &SessionInfo) -> anyhow::Result<()> {
+        self.sessions.insert(session_key(identity, &session.id), serde_json::to_vec(session)?)?;
+        Ok(())
+    }
+
+    pub fn session(&self, identity: &str, id: &str) -> anyhow::Result<Option<SessionInfo>> {
+        self.sessions.get(session_key(identity, id))?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    pub fn sessions(&self, identity: &str) -> anyhow::Result<Vec<SessionInfo>> {
+        self.sessions.scan_prefix(format!("{identity}{SEP}").as_bytes())
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+
+    /// Revoke a session, returning whether it existed
+    pub fn remove_session(&self, identity: &str, id: &str) -> anyhow::Result<bool> {
+        Ok(self.sessions.remove(session_key(identity, id))?.is_some())
+    }
+
+    /// Revoke every session of the identity, or only those a key signed in with
+    pub fn remove_sessions(&self, identity: &str, key: Option<&str>) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for session in self.sessions(identity)? {
+            if key.is_none_or(|k| k == session.key) {
+                self.sessions.remove(session_key(identity, &session.id))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Keys {
+        Keys::open(&sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    fn session(id: &str, key: &str) -> SessionInfo {
+        SessionInfo {
+            id: id.to_string(),
+            key: key.to_string(),
+            created: 0,
+            user_agent: None,
+            ip: None,
+        }
+    }
+
+    fn device_keys(keys: &Keys, identity: &str) -> Vec<String> {
+        keys.devices(identity).unwrap().into_iter().map(|d| d.key).collect()
+    }
+
+    #[test]
+    fn rotate_moves_identity_to_new_key() {
+        let keys = keys();
+        keys.add_session("old", &session("s1", "old")).unwrap();
+        keys.add_session("new", &session("s2", "new")).unwrap();
+
+        assert_eq!(keys.rotate("old", "new").unwrap(), "old");
+        assert_eq!(keys.identity_of("old").unwrap(), None);
+        assert_eq!(keys.identity_of("new").unwrap().as_deref(), Some("old"));
+        assert_eq!(keys.account_of("old").unwrap(), "old");
+        assert_eq!(device_keys(&keys, "old"), vec!["new"]);
+        // The old key's sessions are revoked, the new key's join the identity
+        let sessions: Vec<String> = keys.sessions("old").unwrap().into_iter().map(|s| s.id).collect();
+        assert_eq!(sessions, vec!["s2"]);
+        assert!(keys.sessions("new").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rotate_twice_keeps_identity() {
+        let keys = keys();
+        keys.rotate("a", "b").unwrap();
+        assert_eq!(keys.rotate("b", "c").unwrap(), "a");
+        assert_eq!(keys.identity_of("c").unwrap().as_deref(), Some("a"));
+        assert_eq!(device_keys(&keys, "a"), vec!["c"]);
+        assert!(keys.rotate("a", "d").is_err());
+    }
+
+    #[test]
+    fn rotate_refuses_used_keys() {
+        let keys = keys();
+        assert!(keys.rotate("a", "a").is_err());
+
+        keys.register("b", None).unwrap();
+        assert!(keys.rotate("a", "b").is_err());
+
+        keys.link("c", "d").unwrap();
+        assert!(keys.rotate("a", "c").is_err());
+        assert!(keys.rotate("a", "d").is_err());
+
+        keys.rotate("e", "f").unwrap();
+        assert!(keys.rotate("a", "e").is_err());
+        assert_eq!(keys.identity_of("a").unwrap().as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn link_adds_device() {
+        let keys = keys();
+        keys.add_session("phone", &session("s1", "phone")).unwrap();
+        keys.link("laptop", "phone").unwrap();
+
+        assert_eq!(keys.identity_of("phone").unwrap().as_deref(), Some("laptop"));
+        assert_eq!(keys.identity_of("laptop").unwrap().as_deref(), Some("laptop"));
+        assert_eq!(device_keys(&keys, "laptop"), vec!["laptop", "phone"]);
+        assert_eq!(keys.sessions("laptop").unwrap().len(), 1);
+        assert!(keys.sessions("phone").unwrap().is_empty());
+    }
+
+    #[test]
+    fn link_refuses_used_keys() {
+        let keys = keys();
+        keys.link("a", "b").unwrap();
+        assert!(keys.link("c", "b").is_err());
+
+        keys.register("d", Some("D".to_string())).unwrap();
+        assert!(keys.link("a", "d").is_err());
+        assert!(keys.link("c", "a").is_err());
+
+        keys.remove_device("a", "b").unwrap();
+        assert!(keys.link("c", "b").is_err());
+        assert_eq!(device_keys(&keys, "a"), vec!["a"]);
+    }
+}
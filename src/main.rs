@@ -1,35 +1,79 @@
 mod types;
+mod db;
 mod migrations;
 mod utils;
 mod session_key;
+mod session_store;
+mod cors;
+mod config;
+mod tls;
+mod search;
+mod comments;
+mod favorites;
+mod albums;
+mod tags;
+mod users;
+mod keys;
+mod directory;
+mod refs;
+mod trash;
+mod auth;
 
 use actix_session::Session;
-use actix_web::{cookie::Key, web, App, HttpServer, HttpResponse, Result, HttpRequest, post, get};
-use actix_cors::Cors;
+use actix_web::{cookie::Key, web, App, HttpServer, HttpResponse, HttpRequest, post, get};
 use types::{
     crypto::PublicKey,
-    AnyError,
     VerificationPayload,
+    HandleClaimPayload,
+    RotateKeyPayload,
+    CaptionPayload,
+    TopicInfoPayload,
+    CommentPayload,
+    EditCommentPayload,
+    ReactionPayload,
+    PageQuery,
+    TopicPageQuery,
+    ProfilePayload,
+    LinkDevicePayload,
+    ImageListQuery,
+    NewIndexPayload,
+    IndexPolicyPayload,
     ServerState,
     Args,
+    extractor_error,
     topic::{
         TopicData,
+        TopicListing,
+        Index,
+        IndexPreview,
+        IndexSummary,
+        TaggedMedia,
         MediaUid,
         OwnedTopicId,
+        TopicAddress,
+        normalize_topic,
     },
 };
-use actix_session::{SessionMiddleware, storage::CookieSessionStore};
-use ed25519_dalek::{SigningKey, Signature, Verifier, VerifyingKey};
-use anyhow::anyhow;
+use actix_session::{SessionMiddleware, config::PersistentSession};
+use session_store::{SessionBackend, SledSessionStore};
 use std::path::PathBuf;
+use std::collections::HashSet;
 use acidjson::AcidJson;
 use mime::Mime;
-use rand_core;
 use smol::stream::StreamExt;
-use structopt::StructOpt;
 use actix_multipart::{form::tempfile::TempFile, Field, Multipart};
 use types::ServerErr;
 
+type Result<T, E = ServerErr> = std::result::Result<T, E>;
+use search::SearchQuery;
+use tags::normalize_tag;
+use comments::{Comment, MAX_COMMENT_LEN, MAX_EMOJI_LEN};
+use albums::{AlbumQuery, SmartAlbum};
+use directory::TopicSummary;
+use keys::{SessionInfo, SessionList};
+use trash::{TrashEntry, TrashItem, DAY_SECS};
+use auth::{Caller, verify_signature, challenge_message, issue_challenge, take_challenge};
+
 use crate::utils::{
     mime_and_ext,
     get_image,
@@ -37,19 +81,15 @@ use crate::utils::{
     is_valid_media,
     save_file,
     save_thumbnail,
-    get_tags_for_topic,
-    add_tag_for_topic,
-    rm_tag_for_topic,
+    export_index,
+    read_media_metadata,
+    read_topic,
+    write_topic,
+    topic_owners,
+    preview_media,
+    delete_originals,
 };
 
-fn normalize_topic(topic: &str) -> String {
-    topic.to_lowercase()
-        .replace(" ", "-")
-        .replace("%20", "-")
-        .replace(".", "_")
-        .trim().to_string()
-}
-
 /// Start the thumbnail generator process which generates for all files passed on the channel
 async fn thumbnail_generator(args: &Args) -> smol::channel::Sender<PathBuf> {
     // Concurrently maintain a queue of thumbnails to generate,
@@ -60,10 +100,10 @@ async fn thumbnail_generator(args: &Args) -> smol::channel::Sender<PathBuf> {
 
     let mut thumbnail_path = args.root_dir.clone();
     thumbnail_path.push("thumbnails");
+    let max_thumbnail_size = args.thumbnails.max_size;
 
     smol::spawn(async move {
         while let Some(path) = thumbnail_queue_receiver.next().await {
-            let max_thumbnail_size = 500;
             let res = save_thumbnail(
                 path.clone(), thumbnail_path.clone(),
                 max_thumbnail_size)
@@ -77,65 +117,407 @@ async fn thumbnail_generator(args: &Args) -> smol::channel::Sender<PathBuf> {
     thumbnail_queue_sender
 }
 
-#[get("/generate-key")]
-async fn generate_keys() -> Result<HttpResponse> {
-    let secret = SigningKey::generate(&mut rand_core::OsRng);
-    let encoded = base64::encode(&secret.to_bytes());
-
-    Ok(HttpResponse::Ok().body(encoded))
-}
-
 #[get("/generate-challenge")]
 async fn generate_challenge(
     session: Session,
 ) -> Result<HttpResponse> {
-    let challenge: [u8; 32] = rand::random();
-    // Store the challenge in the session
-    session.insert("challenge", challenge.to_vec())?;
+    let challenge = issue_challenge(&session)?;
 
     let challenge = base64::encode(challenge);
     Ok(HttpResponse::Ok().json(challenge))
 }
 
+/// Verify a signature over `challenge_message` for `auth::AUTHENTICATE_PURPOSE`
 #[post("/authenticate")]
 async fn authenticate(
+    req: HttpRequest,
     session: Session,
     payload: web::Json<VerificationPayload>,
+    data: web::Data<ServerState>,
 ) -> Result<HttpResponse> {
-    // Check if the challenge in the session matches the provided challenge
-    let stored_challenge = session.get::<Vec<u8>>("challenge")?
-        .ok_or(AnyError::from(anyhow!("No challenge found in session!")))?;
-    // Remove the challenge from the session to ensure it can't be used again
-    session.remove("challenge");
+    let origin = auth::origin(&data.args);
+    data.auth_attempts.guard(req.peer_addr(), || {
+        let challenge = take_challenge(&session)?;
+        let msg = challenge_message(&origin, auth::AUTHENTICATE_PURPOSE, &challenge);
+        verify_signature(&payload.public_key, &msg, &payload.signature)
+    })?;
 
-    let public_key = VerifyingKey::from_bytes(&payload.public_key.to_bytes())
-        .map_err(|_| AnyError::from(anyhow!("Invalid public key!")))?;
+    let key = payload.public_key.to_string();
+    let identity = data.keys.identity_of(&key)?
+        .ok_or_else(|| ServerErr::forbidden("Key was rotated, sign in with the new key"))?;
+    let info = SessionInfo {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        key: key.clone(),
+        created: chrono::Utc::now().timestamp(),
+        user_agent: req.headers().get(actix_web::http::header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+    };
+    data.keys.add_session(&identity, &info)?;
 
-    let sig: [u8; 64] = payload.signature[..].try_into()
-        .map_err(|_| AnyError::from(anyhow!("Invalid signature length!")))?;
-    let signature = Signature::from_bytes(&sig);
+    session.renew();
+    session.insert("verified_pubkey", key)?;
+    session.insert("session_id", info.id)?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    if public_key.verify(&stored_challenge, &signature).is_ok() {
-        session.insert("verified_pubkey", payload.public_key.to_string())?;
-        Ok(HttpResponse::Ok().finish())
-    } else {
-        Err(AnyError::from(anyhow!("Signature is invalid!")).into())
+/// Revoke the session the request was made with
+#[post("/logout")]
+async fn logout(
+    session: Session,
+    caller: Caller,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    if let (Some(identity), Some(id)) = (caller.key(), caller.session_id()) {
+        data.keys.remove_session(&identity, id)?;
     }
+    session.purge();
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[get("/tag/{name}")]
+/// Revoke every session of the caller, on all devices
+#[post("/logout-all")]
+async fn logout_all(
+    session: Session,
+    caller: Caller,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let identity = caller_key(&caller)?;
+    let removed = data.keys.remove_sessions(&identity, None)?;
+    session.purge();
+    Ok(HttpResponse::Ok().json(removed))
+}
+
+#[get("/sessions")]
+async fn list_sessions(
+    caller: Caller,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let identity = caller_key(&caller)?;
+    Ok(HttpResponse::Ok().json(SessionList {
+        current: caller.session_id().map(|id| id.to_string()),
+        sessions: data.keys.sessions(&identity)?,
+    }))
+}
+
+#[post("/sessions/{session}/revoke")]
+async fn revoke_session(
+    webpath: web::Path<String>,
+    caller: Caller,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let identity = caller_key(&caller)?;
+    let id = webpath.into_inner();
+    if !data.keys.remove_session(&identity, &id)? {
+        return Err(ServerErr::not_found(format!("Session {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Register a profile for the caller, or update it
+#[post("/keys/register")]
+async fn register_key(
+    caller: Caller,
+    payload: web::Json<ProfilePayload>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let identity = caller_key(&caller)?;
+    let display_name = payload.into_inner().display_name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    let profile = data.keys.register(&identity, display_name)?;
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+#[get("/profile/{id}")]
+async fn get_profile(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let id = owner_key(&data, &webpath.into_inner())?;
+    let profile = data.keys.profile(&id)?
+        .ok_or_else(|| ServerErr::not_found(format!("No profile registered for {}", id)))?;
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+/// Keys that can sign for the caller's account
+#[get("/devices")]
+async fn list_devices(
+    caller: Caller,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let identity = caller_key(&caller)?;
+    Ok(HttpResponse::Ok().json(data.keys.devices(&identity)?))
+}
+
+/// Link the key the caller is signed in with to the account of another
+/// device. The other device signs for `keys::link_purpose` with the
+/// caller's challenge, after which both own the same topics.
+#[post("/devices/link")]
+async fn link_device(
+    req: HttpRequest,
+    session: Session,
+    caller: Caller,
+    payload: web::Json<LinkDevicePayload>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let new_key = caller.device()
+        .ok_or_else(|| ServerErr::unauthenticated("Not verified please authenticate"))?
+        .to_string();
+    if caller.key().as_deref() != Some(new_key.as_str()) || key_in_use(&data, &new_key)? {
+        return Err(ServerErr::conflict("Key is already in use"));
+    }
+
+    let origin = auth::origin(&data.args);
+    data.auth_attempts.guard(req.peer_addr(), || {
+        let challenge = take_challenge(&session)?;
+        let msg = challenge_message(&origin, &keys::link_purpose(&new_key), &challenge);
+        verify_signature(&payload.inviter, &msg, &payload.signature)
+    })?;
+
+    let identity = data.keys.identity_of(&payload.inviter.to_string())?
+        .ok_or_else(|| ServerErr::forbidden("Inviting key was removed from its account"))?;
+    data.keys.link(&identity, &new_key)
+        .map_err(|e| ServerErr::conflict(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(identity))
+}
+
+/// Whether a key has data of its own, which it would leave behind when it
+/// starts signing for another account
+fn key_in_use(
+    data: &ServerState,
+    key: &str,
+) -> Result<bool> {
+    Ok(!data.directory.owned_by(key, None, Some(1))?.topics.is_empty()
+        || data.keys.is_account(key)?
+        || data.handles.handle_of(key)?.is_some()
+        || data.refs.has_media(key)
+        || !data.albums.list(key)?.is_empty()
+        || !data.trash.list(key)?.is_empty())
+}
+
+/// Remove a device from the caller's account, it can't sign in again
+#[post("/devices/remove")]
+async fn remove_device(
+    caller: Caller,
+    payload: web::Json<PublicKey>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let identity = caller_key(&caller)?;
+    data.keys.remove_device(&identity, &payload.to_string())
+        .map_err(|e| ServerErr::bad_request(e.to_string()))?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Rotate to a new key. The old key signs for `keys::rotate_purpose` and
+/// the caller must be signed in with the new key. Topics, handle and profile
+/// stay with the identity while the old key and its sessions stop working.
+#[post("/keys/rotate")]
+async fn rotate_key(
+    req: HttpRequest,
+    session: Session,
+    caller: Caller,
+    payload: web::Json<RotateKeyPayload>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let new_key = payload.new_key.to_string();
+    if caller.device() != Some(new_key.as_str()) {
+        return Err(ServerErr::KeyMismatch("Sign in with the new key to rotate to it".to_string()));
+    }
+    if caller.key().as_deref() != Some(new_key.as_str()) || key_in_use(&data, &new_key)? {
+        return Err(ServerErr::conflict("New key is already in use"));
+    }
+
+    let origin = auth::origin(&data.args);
+    data.auth_attempts.guard(req.peer_addr(), || {
+        let challenge = take_challenge(&session)?;
+        let msg = challenge_message(&origin, &keys::rotate_purpose(&new_key), &challenge);
+        verify_signature(&payload.old_key, &msg, &payload.signature)
+    })?;
+
+    let identity = data.keys.rotate(&payload.old_key.to_string(), &new_key)
+        .map_err(|e| ServerErr::conflict(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(identity))
+}
+
+/// Claim a unique handle for a key, replacing any handle it had before
+#[post("/handle/claim")]
+async fn claim_handle(
+    req: HttpRequest,
+    session: Session,
+    payload: web::Json<HandleClaimPayload>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let handle = users::normalize_handle(&payload.handle)
+        .map_err(|e| ServerErr::bad_request(e.to_string()))?;
+    let origin = auth::origin(&data.args);
+    data.auth_attempts.guard(req.peer_addr(), || {
+        let challenge = take_challenge(&session)?;
+        let msg = challenge_message(&origin, &users::claim_purpose(&handle), &challenge);
+        verify_signature(&payload.public_key, &msg, &payload.signature)
+    })?;
+
+    let identity = data.keys.identity_of(&payload.public_key.to_string())?
+        .ok_or_else(|| ServerErr::forbidden("Key was rotated, sign in with the new key"))?;
+    data.handles.claim(&handle, &identity)
+        .map_err(|e| ServerErr::conflict(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(handle))
+}
+
+#[get("/handle/{handle}")]
+async fn get_handle_key(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let handle = webpath.into_inner();
+    let handle = handle.strip_prefix(users::HANDLE_PREFIX).unwrap_or(&handle).to_lowercase();
+    let key = data.handles.key_of(&handle)?
+        .ok_or_else(|| ServerErr::not_found(format!("Handle {} not found", handle)))?;
+
+    Ok(HttpResponse::Ok().json(key))
+}
+
+/// Handle of the session key, null if it has none
+#[get("/my-handle")]
+async fn get_my_handle(
+    caller: Caller,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(&caller)?;
+    let handle = data.handles.handle_of(&pubkey)?;
+
+    Ok(HttpResponse::Ok().json(handle))
+}
+
+/// Get an index with a preview of media from the topics the session owns
+#[get("/index/{index}")]
 async fn get_index(
     webpath: web::Path<String>,
     data: web::Data<ServerState>,
+    caller: Caller,
 ) -> Result<HttpResponse> {
-    let name = normalize_topic(&webpath.into_inner());
-    let mut path = data.args.root_dir.clone();
-    path.push("indexes");
-    path.push(format!("{}.json", name));
+    index_preview(&data, &webpath.into_inner(), &caller)
+}
+
+/// Alias of /index/{index}
+#[get("/tag/{name}")]
+async fn get_tag_index(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    index_preview(&data, &webpath.into_inner(), &caller)
+}
+
+fn index_preview(
+    data: &ServerState,
+    name: &str,
+    caller: &Caller,
+) -> Result<HttpResponse> {
+    let name = tag_name(data, name)?;
+    let index = data.tags.get_tree(&name)?
+        .ok_or_else(|| ServerErr::not_found(format!("Tag {} not found", name)))?;
+    let children = data.tags.descendants(&name)?;
+
+    // Topics are only readable by their owner
+    let viewer: Option<String> = caller.key();
+    let mut preview = vec![];
+    if let Some(viewer) = &viewer {
+        let mut topics: Vec<&String> = index.topics.iter().collect();
+        topics.sort();
+        for topic_id in topics {
+            let OwnedTopicId { owner_id, .. } = serde_json::from_str(topic_id)?;
+            if &owner_id != viewer {
+                continue;
+            }
+            let Some(td) = read_topic(&data.topic_db, topic_id)? else { continue };
+            preview.extend(preview_media(&td));
+        }
+    }
+
+    // Media tagged on their own, also only from the viewer's topics
+    let mut media = std::collections::BTreeSet::new();
+    if let Some(viewer) = &viewer {
+        for tag in std::iter::once(&name).chain(children.iter()) {
+            let tagged = data.tags.tagged_media(tag)?;
+            for (topic_id, uid) in tagged {
+                let OwnedTopicId { topic, owner_id } = serde_json::from_str(&topic_id)?;
+                if &owner_id == viewer {
+                    media.insert(TaggedMedia { topic, uid });
+                }
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(IndexPreview {
+        index,
+        preview,
+        children,
+        media: media.into_iter().collect(),
+    }))
+}
+
+#[get("/search")]
+async fn get_search_results(
+    query: web::Query<SearchQuery>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    if search::tokenize(&query.q).is_empty() {
+        return Err(ServerErr::bad_request("Search query can't be empty"));
+    }
+
+    // Docs of a topic are only found by its owner or once it is public,
+    // docs without an owner like tag indexes are found by everyone
+    let viewer: Option<String> = caller.key();
+    let results = data.search.search(&query, |doc| match (&doc.owner, &doc.topic) {
+        (None, _) => Ok(true),
+        (Some(owner), _) if viewer.as_ref() == Some(owner) => Ok(true),
+        (Some(owner), Some(topic)) => data.directory.is_public(owner, topic),
+        (Some(_), None) => Ok(false),
+    })?;
+
+    Ok(HttpResponse::Ok().json(results))
+}
 
-    let index = smol::fs::read_to_string(path).await?;
+/// Export and reindex a tag index after it changed, None if it was removed.
+/// The tag facet is updated for each of the topic ids that were (un)tagged.
+async fn index_changed<'a>(
+    data: &ServerState,
+    tag: &str,
+    index: Option<&Index>,
+    topics: impl IntoIterator<Item = &'a String>,
+) -> anyhow::Result<()> {
+    export_index(&data.args.root_dir, tag, index).await?;
 
-    Ok(HttpResponse::Ok().body(index))
+    match index {
+        Some(_) => data.search.index_tag(tag)?,
+        None => data.search.remove_tag(tag)?,
+    }
+
+    for topic_id in topics {
+        let OwnedTopicId { topic, owner_id } = serde_json::from_str(topic_id)?;
+        let tags = data.tags.tags_for_topic(topic_id)?;
+        data.search.set_topic_tags(&owner_id, &topic, &tags)?;
+    }
+    Ok(())
+}
+
+/// Refresh the media tag facet of media whose tags changed
+fn media_tags_changed(
+    data: &ServerState,
+    tagged: &[(String, MediaUid)],
+) -> anyhow::Result<()> {
+    for (topic_id, media) in tagged {
+        let OwnedTopicId { topic, owner_id } = serde_json::from_str(topic_id)?;
+        let tags = data.tags.tags_for_media(topic_id, media)?;
+        data.search.set_media_tags(&owner_id, &topic, media, &tags)?;
+    }
+    Ok(())
 }
 
 #[get("/img/{name}")]
@@ -172,158 +554,1712 @@ async fn get_image_thumbnail(
         .body(image))
 }
 
-#[post("rm-tag/{topic}/{tag}")]
+/// Topic owners can untag their topic and index owners can remove the
+/// topic of every owner of the name
+async fn rm_tag(
+    data: &ServerState,
+    topic: &str,
+    tag: &str,
+    caller: &Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(caller)?;
+    let topic = normalize_topic(topic);
+    let tag = &tag_name(data, tag)?;
+    let index = find_index(data, tag)?;
+    let topic_ids: Vec<String> = if can_write_topic(data, &topic, &pubkey)? {
+        vec![OwnedTopicId::new(&topic, &pubkey).to_string()?]
+    } else if index.is_owner(&pubkey) {
+        index.topics.iter()
+            .filter(|id| serde_json::from_str::<OwnedTopicId>(id).is_ok_and(|id| id.topic == topic))
+            .cloned()
+            .collect()
+    } else {
+        return Err(ServerErr::forbidden("Only the topic or index owner can remove a tag"));
+    };
+
+    for topic_id in topic_ids.iter() {
+        let index = data.tags.rm_tag(topic_id, tag)
+            .map_err(|e| ServerErr::not_found(e.to_string()))?;
+        index_changed(data, tag, Some(&index), [topic_id]).await?;
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Topic owners can tag their topic if the index policy lets them
+async fn add_tag(
+    data: &ServerState,
+    topic: &str,
+    tag: &str,
+    caller: &Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(caller)?;
+    let topic = normalize_topic(topic);
+    let tag = &tag_name(data, tag)?;
+    if !can_write_topic(data, &topic, &pubkey)? {
+        return Err(ServerErr::forbidden("Only the topic owner can tag a topic"));
+    }
+    if !can_add_to_tag(data, tag, &pubkey)? {
+        return Err(ServerErr::forbidden("Index is not open to new topics"));
+    }
+
+    let topic_id = OwnedTopicId::new(&topic, &pubkey).to_string()?;
+    let index = data.tags.add_tag(&topic_id, tag, &pubkey)?;
+    index_changed(data, tag, Some(&index), [&topic_id]).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("{topic}/remove-tag")]
 async fn rm_tag_from_topic(
+    webpath: web::Path<String>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    rm_tag(&data, &webpath.into_inner(), &payload.into_inner(), &caller).await
+}
+
+#[post("{topic}/new-tag")]
+async fn add_tag_to_topic(
+    webpath: web::Path<String>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    add_tag(&data, &webpath.into_inner(), &payload.into_inner(), &caller).await
+}
+
+/// Alias of {topic}/remove-tag
+#[post("rm-tag/{topic}/{tag}")]
+async fn rm_tag_from_topic_path(
     webpath: web::Path<(String, String)>,
     data: web::Data<ServerState>,
+    caller: Caller,
 ) -> Result<HttpResponse> {
     let (topic, tag) = webpath.into_inner();
-    let topic = normalize_topic(&topic);
-    rm_tag_for_topic(&data.args.root_dir, topic, tag).await
-        .map_err(|e| AnyError::from(e))?;
-
-    Ok(HttpResponse::Ok().finish())
+    rm_tag(&data, &topic, &tag, &caller).await
 }
 
+/// Alias of {topic}/new-tag
 #[post("new-tag/{topic}/{tag}")]
-async fn add_tag_to_topic(
+async fn add_tag_to_topic_path(
     webpath: web::Path<(String, String)>,
     data: web::Data<ServerState>,
+    caller: Caller,
 ) -> Result<HttpResponse> {
     let (topic, tag) = webpath.into_inner();
-    let topic = normalize_topic(&topic);
-    add_tag_for_topic(&data.args.root_dir, topic, tag).await
-        .map_err(|e| AnyError::from(e))?;
-
-    Ok(HttpResponse::Ok().finish())
+    add_tag(&data, &topic, &tag, &caller).await
 }
 
-#[get("{topic}/tags")]
-async fn get_tag_list(
-    webpath: web::Path<String>,
+/// Media level tags of every tagged media in a topic
+#[get("{id}/{topic}/media-tags")]
+async fn get_topic_media_tags(
+    webpath: web::Path<(String, String)>,
     data: web::Data<ServerState>,
+    caller: Caller,
 ) -> Result<HttpResponse> {
-    let topic = normalize_topic(&webpath.into_inner());
-    let path = data.args.root_dir.clone();
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let topic_id = readable_topic_id(&data, &id, &topic, &caller)?;
 
-    let tags = get_tags_for_topic(&path, &topic).await
-        .map_err(|e| AnyError::from(e))?;
+    let tags = data.tags.media_tags_for_topic(&topic_id)?;
 
     Ok(HttpResponse::Ok().json(tags))
 }
 
-#[get("{id}/{topic}/images")]
-async fn get_image_list_by_id(
-    webpath: web::Path<(String, String)>,
+#[get("{id}/{topic}/media-tags/{media}")]
+async fn get_media_tags(
+    webpath: web::Path<(String, String, MediaUid)>,
     data: web::Data<ServerState>,
-    session: Session,
+    caller: Caller,
 ) -> Result<HttpResponse> {
-    log::debug!("Getting image list");
-    let (id, topic) = &webpath.into_inner();
-    let topic = normalize_topic(topic);
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
 
-    is_verified(&id, &session)?;
-    log::debug!("Verified");
+    let tags = data.tags.tags_for_media(&topic_id, &media)?;
 
-    let topic_id = OwnedTopicId {
-        topic: topic.clone(),
-        owner_id: id.clone(),
-    }.to_string()?;
-    log::debug!("Topic id: {}", topic_id);
-    let image_list = if let Some(bytes) = data.topic_db.get(&topic_id)
-        .map_err(|e| ServerErr::from(e))?
-    {
-        let td: TopicData = serde_json::from_slice(bytes.as_ref())?;
-        log::debug!("Topic data: {:?}", td.list());
-        td.list()
-    } else {
-        vec![]
-    };
-    log::debug!("Image list: {:?}", image_list);
-
-    Ok(HttpResponse::Ok().json(image_list))
+    Ok(HttpResponse::Ok().json(tags))
 }
 
-/*
-async fn image_list(path: PathBuf) -> Result<Vec<MediaUid>> {
-    // Read the TopicData file to get the image names
-    let raw_topic_data = smol::fs::read(path).await?;
-    let topic_data: TopicData = serde_json::from_slice(&raw_topic_data)?;
-    let image_names = topic_data.list();
+/// Topic owners can tag media in their topic if the index policy lets them
+#[post("{id}/{topic}/media-tags/{media}/new")]
+async fn add_media_tag(
+    webpath: web::Path<(String, String, MediaUid)>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+    let topic_id = topic_with_media(&data, &id, &topic, &media)?;
+    let tag = &tag_name(&data, &payload.into_inner())?;
+    if !can_add_to_tag(&data, tag, &id)? {
+        return Err(ServerErr::forbidden("Index is not open to new media"));
+    }
 
-    Ok(image_names)
+    let index = data.tags.add_media_tag(&topic_id, &media, tag, &id)?;
+    export_index(&data.args.root_dir, tag, Some(&index)).await
+        .and_then(|_| data.search.index_tag(tag))
+        .and_then(|_| media_tags_changed(&data, &[(topic_id, media)]))?;
+
+    Ok(HttpResponse::Ok().finish())
 }
-*/
 
-#[post("{id}/{topic}/new-image")]
-async fn upload_image_by_id(
-    req: HttpRequest,
-    webpath: web::Path<(String, String)>,
-    mut payload: Multipart,
+/// Topic owners and index owners can untag media
+#[post("{id}/{topic}/media-tags/{media}/remove")]
+async fn rm_media_tag(
+    webpath: web::Path<(String, String, MediaUid)>,
+    payload: web::Json<String>,
     data: web::Data<ServerState>,
-    session: Session,
+    caller: Caller,
 ) -> Result<HttpResponse> {
-    let (id, topic) = &webpath.into_inner();
-    let topic = normalize_topic(topic);
-    let root_dir = data.args.root_dir.clone();
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = topic_with_media(&data, &id, &topic, &media)?;
+    let tag = &tag_name(&data, &payload.into_inner())?;
+    if pubkey != id && !find_index(&data, tag)?.is_owner(&pubkey) {
+        return Err(ServerErr::forbidden("Only the topic or index owner can remove a tag"));
+    }
 
-    let topic_id = OwnedTopicId {
-        topic: topic.clone(),
-        owner_id: id.clone(),
-    }.to_string()?;
-    log::debug!("Topic id: {}", topic_id);
+    data.tags.rm_media_tag(&topic_id, &media, tag)
+        .and_then(|_| media_tags_changed(&data, &[(topic_id, media)]))?;
 
-    while let Some(mut field) = payload.try_next().await? {
-        let (mime, ext) = mime_and_ext(&field)?;
-        is_valid_media(&mime)?;
-        is_verified(&id, &session)?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-        // Add the image if its not already in the root dir
-        let image_fname = save_file(
-            &root_dir,
-            field,
-            ext,
-            data.thumbnail_sender.clone()).await?;
+/// All indexes with their topic counts
+#[get("/all-indexes")]
+async fn get_all_indexes(
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let indexes: Vec<IndexSummary> = data.tags.all()?
+        .into_iter()
+        .map(|index| IndexSummary {
+            topics: index.topics.len(),
+            name: index.name,
+        })
+        .collect();
 
-        // Add media to topic db
-        let td = if let Some(bytes) = data.topic_db.get(&topic_id)
-            .map_err(|e| ServerErr::from(e))?
-        {
-            let mut td: TopicData = serde_json::from_slice(bytes.as_ref())?;
-            td.add(vec![image_fname]);
-            td
-        } else {
-            TopicData::new(topic.clone(), None, vec![image_fname])
-        };
-        let bytes = serde_json::to_vec(&td)?;
+    Ok(HttpResponse::Ok().json(indexes))
+}
 
-        data.topic_db.insert(&topic_id, bytes)
-            .map_err(|e| ServerErr::from(e))?;
+#[post("/new-index")]
+async fn create_index(
+    payload: web::Json<NewIndexPayload>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(&caller)?;
+    let NewIndexPayload { name, topics } = payload.into_inner();
+    let name = new_tag_name(&name)?;
+    if !can_add_to_tag(&data, &name, &pubkey)? {
+        return Err(ServerErr::forbidden("Parent index is not open to new tags"));
+    }
+    let topics: HashSet<String> = topics.iter()
+        .map(|t| normalize_topic(t))
+        .filter(|t| !t.is_empty())
+        .collect();
+    let mut topic_ids = HashSet::new();
+    for topic in topics.iter() {
+        if !can_write_topic(&data, topic, &pubkey)? {
+            return Err(ServerErr::forbidden(format!("Only the topic owner can tag {}", topic)));
+        }
+        topic_ids.insert(OwnedTopicId::new(topic, &pubkey).to_string()?);
     }
 
-    Ok(HttpResponse::Ok().body("Success"))
+    let index = data.tags.create(&name, topic_ids, &pubkey)
+        .map_err(|e| ServerErr::bad_request(e.to_string()))?;
+    index_changed(&data, &name, Some(&index), index.topics.iter()).await?;
+
+    Ok(HttpResponse::Ok().json(index))
 }
 
-/// Check that the id, owner and session public key all match
-fn is_verified(
-    id: &str,
-    session: &Session,
-) -> Result<()> {
-    // Session key should match id
-    let pubkey: String = session.get("verified_pubkey")?
-        .ok_or_else(|| actix_web::error::ErrorInternalServerError("Not verified please authenticate"))?;
+#[post("/index/{index}/rename")]
+async fn rename_index(
+    webpath: web::Path<String>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let name = tag_name(&data, &webpath.into_inner())?;
+    let new_name = new_tag_name(&payload.into_inner())?;
+    is_index_owner(&data, &name, &caller)?;
+    // Child tags move along with their parent, so they must be the caller's too
+    for child in data.tags.descendants(&name)? {
+        is_index_owner(&data, &child, &caller)
+            .map_err(|_| ServerErr::forbidden(format!("Child tag {} belongs to another key", child)))?;
+    }
+    if !can_add_to_tag(&data, &new_name, &caller_key(&caller)?)? {
+        return Err(ServerErr::forbidden("Parent index is not open to new tags"));
+    }
 
-    // check pubkey matches id
-    pubkey.eq(id)
-        .then(|| ())
-        .ok_or_else(|| actix_web::error::ErrorInternalServerError("Verified public key for session does not match provided id"))
+    let moved = data.tags.rename(&name, &new_name)
+        .map_err(|e| ServerErr::bad_request(e.to_string()))?;
+    for (old_name, index) in moved.iter() {
+        index_changed(&data, old_name, None, []).await?;
+        index_changed(&data, &index.name, Some(index), index.topics.iter()).await?;
+        data.tags.tagged_media(&index.name)
+            .and_then(|tagged| media_tags_changed(&data, &tagged))?;
+    }
+
+    Ok(HttpResponse::Ok().json(moved.into_iter().map(|(_, index)| index).collect::<Vec<_>>()))
+}
+
+/// Move all topics of the index into another one, the old name becomes an alias
+#[post("/index/{index}/merge")]
+async fn merge_index(
+    webpath: web::Path<String>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let name = tag_name(&data, &webpath.into_inner())?;
+    let target = tag_name(&data, &payload.into_inner())?;
+    // Both sides lose control over their topics so both must be owned
+    is_index_owner(&data, &name, &caller)?;
+    is_index_owner(&data, &target, &caller)?;
+
+    let source = find_index(&data, &name)?;
+    let tagged = data.tags.tagged_media(&name)?;
+    let index = data.tags.merge(&name, &target)
+        .map_err(|e| ServerErr::bad_request(e.to_string()))?;
+    index_changed(&data, &name, None, []).await?;
+    index_changed(&data, &target, Some(&index), source.topics.iter()).await?;
+    media_tags_changed(&data, &tagged)?;
+
+    Ok(HttpResponse::Ok().json(index))
+}
+
+#[post("/index/{index}/delete")]
+async fn delete_index(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let name = tag_name(&data, &webpath.into_inner())?;
+    is_index_owner(&data, &name, &caller)?;
+
+    let tagged = data.tags.tagged_media(&name)?;
+    let index = data.tags.delete(&name)
+        .map_err(|e| ServerErr::not_found(e.to_string()))?;
+    index_changed(&data, &name, None, index.topics.iter()).await?;
+    media_tags_changed(&data, &tagged)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Set who can add topics to the index
+#[post("/index/{index}/policy")]
+async fn set_index_policy(
+    webpath: web::Path<String>,
+    payload: web::Json<IndexPolicyPayload>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let name = tag_name(&data, &webpath.into_inner())?;
+    is_index_owner(&data, &name, &caller)?;
+
+    let IndexPolicyPayload { policy, invited } = payload.into_inner();
+    // Invites are for accounts, whichever device key or handle names them
+    let invited = invited.iter()
+        .map(|id| owner_key(&data, id))
+        .collect::<Result<_>>()?;
+    let index = data.tags.set_policy(&name, policy, invited)?;
+    export_index(&data.args.root_dir, &name, Some(&index)).await?;
+
+    Ok(HttpResponse::Ok().json(index))
+}
+
+/// Normalize a tag from a request and follow aliases to its current name
+fn tag_name(
+    data: &ServerState,
+    tag: &str,
+) -> Result<String> {
+    let tag = new_tag_name(tag)?;
+    Ok(data.tags.resolve(&tag)?)
+}
+
+/// Normalize a tag name which doesn't need to exist yet
+fn new_tag_name(tag: &str) -> Result<String> {
+    let tag = normalize_tag(tag);
+    if tag.is_empty() {
+        return Err(ServerErr::bad_request("Tag name can't be empty"));
+    }
+    Ok(tag)
+}
+
+fn find_index(
+    data: &ServerState,
+    tag: &str,
+) -> Result<Index> {
+    data.tags.get(tag)?
+        .ok_or_else(|| ServerErr::not_found(format!("Tag {} not found", tag)))
+}
+
+/// Check the session key owns the index. Indexes from before ownership
+/// existed have no owner and can't be renamed or deleted.
+fn is_index_owner(
+    data: &ServerState,
+    tag: &str,
+    caller: &Caller,
+) -> Result<()> {
+    let pubkey = caller_key(caller)?;
+    find_index(data, tag)?
+        .is_owner(&pubkey)
+        .then_some(())
+        .ok_or_else(|| ServerErr::forbidden("Only the index owner can change it"))
+}
+
+/// Whether the key can add to the tag, which the index of the tag and of
+/// each of its parents has to allow
+fn can_add_to_tag(
+    data: &ServerState,
+    tag: &str,
+    pubkey: &str,
+) -> Result<bool> {
+    for name in tags::with_ancestors([&tag.to_string()]) {
+        if data.tags.get(&name)?.is_some_and(|index| !index.can_add(pubkey)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether the key can change the topic, which for now means owning it
+fn can_write_topic(
+    data: &ServerState,
+    topic: &str,
+    pubkey: &str,
+) -> Result<bool> {
+    let topic_id = OwnedTopicId {
+        topic: topic.to_string(),
+        owner_id: pubkey.to_string(),
+    }.to_string()?;
+    Ok(data.topic_db.contains_key(&topic_id)?)
+}
+
+/// Tags of the session's own topic
+#[get("{topic}/tags")]
+async fn get_tag_list(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(&caller)?;
+    let topic_id = OwnedTopicId::new(&webpath.into_inner(), &pubkey).to_string()?;
+
+    let tags = data.tags.tags_for_topic(&topic_id)?;
+
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[get("{id}/{topic}/tags")]
+async fn get_tag_list_by_id(
+    webpath: web::Path<(String, String)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let topic_id = readable_topic_id(&data, &id, &topic, &caller)?;
+
+    let tags = data.tags.tags_for_topic(&topic_id)?;
+
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[get("{id}/{topic}/images")]
+async fn get_image_list_by_id(
+    webpath: web::Path<(String, String)>,
+    query: web::Query<ImageListQuery>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    log::debug!("Getting image list");
+    let (id, topic) = &webpath.into_inner();
+    let id = &owner_key(&data, id)?;
+    let topic = normalize_topic(topic);
+    // Topics without media yet list as empty under the given id
+    let id = &resolve_owner(&data, id, &topic).unwrap_or_else(|_| id.clone());
+
+    let topic_id = OwnedTopicId {
+        topic: topic.clone(),
+        owner_id: id.clone(),
+    }.to_string()?;
+    log::debug!("Topic id: {}", topic_id);
+    let td = read_topic(&data.topic_db, &topic_id)?;
+
+    // Renamed and merged topics redirect to where they live now
+    if td.is_none() {
+        let moved = data.directory.redirect(id, &topic)?;
+        if let Some(to) = moved {
+            return Ok(HttpResponse::PermanentRedirect()
+                .append_header(("Location", format!("/{}/{}/images", encode_key(id), to)))
+                .finish());
+        }
+    }
+
+    // Public topics can be read by anyone
+    if !td.as_ref().is_some_and(|td| td.public) {
+        is_verified(id, &caller)?;
+        log::debug!("Verified");
+    }
+
+    let mut listing = match td {
+        Some(td) => {
+            log::debug!("Topic data: {:?}", td.list());
+            td.listing()
+        }
+        None => TopicListing::default(),
+    };
+    if query.favorites || query.min_rating.is_some() {
+        let marks = data.favorites.marks_by(&topic_id, &caller_key(&caller)?)?;
+        listing.media.retain(|m| {
+            let Some(mark) = marks.get(&m.uid) else { return false };
+            (!query.favorites || mark.favorite)
+                && query.min_rating.is_none_or(|n| mark.stars.unwrap_or(0) >= n)
+        });
+    }
+    log::debug!("Image list: {:?}", listing);
+
+    Ok(HttpResponse::Ok().json(listing))
+}
+
+#[get("{id}/{topic}/history")]
+async fn get_topic_history(
+    webpath: web::Path<(String, String)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = &webpath.into_inner();
+    let id = &owner_key(&data, id)?;
+    let topic = normalize_topic(topic);
+    let id = resolve_owner(&data, id, &topic)?;
+    is_verified(&id, &caller)?;
+
+    let topic_id = OwnedTopicId::new(&topic, &id).to_string()?;
+    let td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic))?;
+
+    Ok(HttpResponse::Ok().json(td.revs))
+}
+
+/// Topics owned by the session key
+#[get("/topics")]
+async fn list_own_topics(
+    query: web::Query<TopicPageQuery>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(&caller)?;
+    let TopicPageQuery { after, limit } = query.into_inner();
+    let page = data.directory.owned_by(&pubkey, after, limit)?;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+/// Public topics of every owner
+#[get("/directory")]
+async fn list_public_topics(
+    query: web::Query<TopicPageQuery>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse> {
+    let TopicPageQuery { after, limit } = query.into_inner();
+    let page = data.directory.public(after, limit)?;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+/// Make a topic public or private again
+#[post("{id}/{topic}/visibility")]
+async fn set_topic_visibility(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<bool>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let topic_id = existing_topic_id(&data, &id, &topic)?;
+    let mut td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(normalize_topic(&topic)))?;
+    td.public = payload.into_inner();
+    save_topic(&data, &id, &topic_id, &mut td)?;
+
+    Ok(HttpResponse::Ok().json(TopicSummary::new(&id, &td)))
+}
+
+/// Check a new topic name is usable and not taken by the owner
+fn new_topic_id(
+    data: &ServerState,
+    owner: &str,
+    topic: &str,
+) -> Result<String> {
+    if topic.is_empty() {
+        return Err(ServerErr::bad_request("Topic name can't be empty"));
+    }
+    let topic_id = OwnedTopicId::new(topic, owner).to_string()?;
+    if data.topic_db.contains_key(&topic_id)? {
+        return Err(ServerErr::conflict(format!("Topic {} already exists", topic)));
+    }
+    Ok(topic_id)
+}
+
+/// Move everything kept per topic from one owned topic to another after the
+/// topic data moved, leaving a redirect behind
+async fn move_topic_data(
+    data: &ServerState,
+    owner: &str,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<()> {
+    let from_id = OwnedTopicId::new(from, owner).to_string()?;
+    let to_id = OwnedTopicId::new(to, owner).to_string()?;
+    data.comments.move_topic(&from_id, &to_id)?;
+    data.favorites.move_topic(&from_id, &to_id)?;
+    data.tags.move_topic_media(&from_id, &to_id, false)?;
+    data.search.move_topic(owner, from, owner, to, false)?;
+    data.directory.remove(owner, from)?;
+    data.directory.set_redirect(owner, from, to)?;
+
+    for tag in data.tags.tags_for_topic(&from_id)? {
+        data.tags.add_tag(&to_id, &tag, owner)?;
+        let index = data.tags.rm_tag(&from_id, &tag)?;
+        index_changed(data, &tag, Some(&index), [&to_id]).await?;
+    }
+    data.search.set_topic_tags(owner, to, &data.tags.tags_for_topic(&to_id)?)?;
+    Ok(())
+}
+
+/// Rename an owned topic, the old name redirects to the new one
+#[post("{id}/{topic}/rename")]
+async fn rename_topic(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let topic = normalize_topic(&topic);
+    let from_id = existing_topic_id(&data, &id, &topic)?;
+    let new_topic = normalize_topic(&payload.into_inner());
+    let to_id = new_topic_id(&data, &id, &new_topic)?;
+
+    let mut td = read_topic(&data.topic_db, &from_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+    td.name = new_topic.clone();
+    save_topic(&data, &id, &to_id, &mut td)?;
+    remove_topic_entry(&data, &id, &from_id)?;
+    move_topic_data(&data, &id, &topic, &new_topic).await?;
+
+    Ok(HttpResponse::Ok().json(TopicSummary::new(&id, &td)))
+}
+
+/// Copy a topic the session can read into its own namespace along with its
+/// history, optionally under a new name
+#[post("{id}/{topic}/fork")]
+async fn fork_topic(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<Option<String>>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+
+    let from_id = existing_topic_id(&data, &id, &topic)?;
+    let OwnedTopicId { topic, owner_id } = serde_json::from_str(&from_id)?;
+    let mut td = read_topic(&data.topic_db, &from_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+    if owner_id != pubkey && !td.public {
+        return Err(ServerErr::forbidden("Only public topics can be forked"));
+    }
+
+    let new_topic = payload.into_inner()
+        .map(|name| normalize_topic(&name))
+        .unwrap_or_else(|| topic.clone());
+    let to_id = new_topic_id(&data, &pubkey, &new_topic)?;
+
+    td.name = new_topic.clone();
+    td.public = false;
+    save_topic(&data, &pubkey, &to_id, &mut td)?;
+    data.tags.move_topic_media(&from_id, &to_id, true)
+        .and_then(|_| data.search.move_topic(&owner_id, &topic, &pubkey, &new_topic, true))?;
+    // The fork keeps the topic tags its indexes let the new owner add
+    for tag in data.tags.tags_for_topic(&from_id)? {
+        if can_add_to_tag(&data, &tag, &pubkey)? {
+            let index = data.tags.add_tag(&to_id, &tag, &pubkey)?;
+            index_changed(&data, &tag, Some(&index), [&to_id]).await?;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(TopicSummary::new(&pubkey, &td)))
+}
+
+/// Append the media of an owned topic to another one of the same owner in
+/// order. The merged topic is removed and redirects to the target.
+#[post("{id}/{topic}/merge")]
+async fn merge_topic(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let topic = normalize_topic(&topic);
+    let target = normalize_topic(&payload.into_inner());
+    if topic == target {
+        return Err(ServerErr::bad_request("Can't merge a topic into itself"));
+    }
+    let from_id = existing_topic_id(&data, &id, &topic)?;
+    let to_id = existing_topic_id(&data, &id, &target)?;
+    let source = read_topic(&data.topic_db, &from_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+    let mut td = read_topic(&data.topic_db, &to_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(target.clone()))?;
+
+    td.add(source.list());
+    let captions = td.captions();
+    for (uid, caption) in source.captions() {
+        if !captions.contains_key(&uid) {
+            td.set_caption(uid, caption);
+        }
+    }
+    save_topic(&data, &id, &to_id, &mut td)?;
+    remove_topic_entry(&data, &id, &from_id)?;
+    move_topic_data(&data, &id, &topic, &target).await
+        .and_then(|_| data.search.index_topic(&id, &td, &data.tags.tags_for_topic(&to_id)?))?;
+
+    Ok(HttpResponse::Ok().json(TopicSummary::new(&id, &td)))
+}
+
+/// Owned topics a bare topic name can refer to. The session's own topic wins,
+/// otherwise every owner of the name is a candidate.
+fn topic_candidates(
+    data: &ServerState,
+    topic: &str,
+    caller: &Caller,
+) -> Result<Vec<TopicAddress>> {
+    let topic = normalize_topic(topic);
+    let viewer: Option<String> = caller.key();
+    let mut owners = topic_owners(&data.topic_db, &topic)?;
+    if let Some(viewer) = viewer.clone().filter(|v| owners.contains(v)) {
+        owners = vec![viewer];
+    }
+
+    // A name the viewer renamed away from points to the new name
+    if let (true, Some(viewer)) = (owners.is_empty(), viewer) {
+        let moved = data.directory.redirect(&viewer, &topic)?;
+        if let Some(to) = moved {
+            return Ok(vec![OwnedTopicId::new(&to, &viewer).into()]);
+        }
+    }
+
+    Ok(owners.into_iter()
+        .map(|owner| OwnedTopicId::new(&topic, &owner).into())
+        .collect())
+}
+
+/// Canonical address of a bare topic name, 300 with the candidates if the
+/// name is owned by several keys
+#[get("/resolve/{topic}")]
+async fn resolve_topic(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let topic = webpath.into_inner();
+    let mut candidates = topic_candidates(&data, &topic, &caller)?;
+    match candidates.len() {
+        0 => Err(ServerErr::TopicNotFound(normalize_topic(&topic))),
+        1 => Ok(HttpResponse::Ok().json(candidates.remove(0))),
+        _ => Ok(HttpResponse::MultipleChoices().json(candidates)),
+    }
+}
+
+/// Redirect a bare topic name to the image list of its canonical owned topic
+#[get("/topic/{topic}")]
+async fn redirect_topic(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let topic = webpath.into_inner();
+    let mut candidates = topic_candidates(&data, &topic, &caller)?;
+    match candidates.len() {
+        0 => Err(ServerErr::TopicNotFound(normalize_topic(&topic))),
+        1 => {
+            let TopicAddress { owner, topic } = candidates.remove(0);
+            Ok(HttpResponse::TemporaryRedirect()
+                .append_header(("Location", format!("/{}/{}/images", encode_key(&owner), topic)))
+                .finish())
+        }
+        _ => Ok(HttpResponse::MultipleChoices().json(candidates)),
+    }
+}
+
+/// Percent encode the characters of a base64 key that aren't allowed in a path segment
+fn encode_key(key: &str) -> String {
+    key.replace('+', "%2B")
+        .replace('/', "%2F")
+        .replace('=', "%3D")
+}
+
+#[post("{id}/{topic}/caption")]
+async fn set_media_caption(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<CaptionPayload>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = &webpath.into_inner();
+    let id = &owner_key(&data, id)?;
+    let topic = normalize_topic(topic);
+    is_verified(id, &caller)?;
+
+    let topic_id = OwnedTopicId {
+        topic: topic.clone(),
+        owner_id: id.clone(),
+    }.to_string()?;
+    let mut td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+
+    let CaptionPayload { media, caption } = payload.into_inner();
+    if !td.contains(&media) {
+        return Err(ServerErr::bad_request(format!("Media {} is not in topic", media)));
+    }
+    let caption = caption.trim().to_string();
+    td.set_caption(media.clone(), caption.clone());
+    save_topic(&data, id, &topic_id, &mut td)?;
+
+    data.search.set_caption(id, &topic, &media, (!caption.is_empty()).then_some(caption))?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("{id}/{topic}/info")]
+async fn set_topic_info(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<TopicInfoPayload>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = &webpath.into_inner();
+    let id = &owner_key(&data, id)?;
+    let topic = normalize_topic(topic);
+    is_verified(id, &caller)?;
+
+    let topic_id = OwnedTopicId {
+        topic: topic.clone(),
+        owner_id: id.clone(),
+    }.to_string()?;
+    let mut td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+
+    let TopicInfoPayload { title, description, cover } = payload.into_inner();
+    if let Some(title) = title {
+        td.set_title(title.trim().to_string());
+    }
+    if let Some(description) = description {
+        td.set_description(description.trim().to_string());
+    }
+    if let Some(cover) = cover {
+        if cover.is_empty() {
+            td.set_cover(None);
+        } else if td.contains(&cover) {
+            td.set_cover(Some(cover));
+        } else {
+            return Err(ServerErr::bad_request(format!("Media {} is not in topic", cover)));
+        }
+    }
+    save_topic(&data, id, &topic_id, &mut td)?;
+
+    let tags = data.tags.tags_for_topic(&topic_id)?;
+    data.search.index_topic(id, &td, &tags)?;
+
+    Ok(HttpResponse::Ok().json(td.info()))
+}
+
+/*
+async fn image_list(path: PathBuf) -> Result<Vec<MediaUid>> {
+    // Read the TopicData file to get the image names
+    let raw_topic_data = smol::fs::read(path).await?;
+    let topic_data: TopicData = serde_json::from_slice(&raw_topic_data)?;
+    let image_names = topic_data.list();
+
+    Ok(image_names)
+}
+*/
+
+#[post("{id}/{topic}/new-image")]
+async fn upload_image_by_id(
+    req: HttpRequest,
+    webpath: web::Path<(String, String)>,
+    mut payload: Multipart,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = &webpath.into_inner();
+    let id = &owner_key(&data, id)?;
+    let topic = normalize_topic(topic);
+    let root_dir = data.args.root_dir.clone();
+
+    let topic_id = OwnedTopicId {
+        topic: topic.clone(),
+        owner_id: id.clone(),
+    }.to_string()?;
+    log::debug!("Topic id: {}", topic_id);
+
+    let tags = data.tags.tags_for_topic(&topic_id)?;
+
+    // Refuse what can't fit before reading any of it
+    is_verified(id, &caller)?;
+    let max_request = data.args.uploads.max_request_bytes;
+    let length = req.headers().get(actix_web::http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if length.is_some_and(|length| length > max_request) {
+        return Err(ServerErr::PayloadTooLarge(format!("Uploads can be at most {} bytes", max_request)));
+    }
+    upload_limit(&data, id, max_request)?;
+    let mut request_left = max_request;
+
+    while let Some(mut field) = payload.try_next().await? {
+        let (mime, ext) = mime_and_ext(&field)?;
+        is_valid_media(&mime)?;
+
+        // Add the image if its not already in the root dir
+        let (limit, too_large) = upload_limit(&data, id, request_left)?;
+        let (image_fname, size) = save_file(
+            &root_dir,
+            field,
+            ext,
+            data.thumbnail_sender.clone(),
+            limit,
+            too_large).await?;
+        request_left -= size;
+        let meta = read_media_metadata(root_dir.join(&image_fname), &mime).await;
+
+        // Add media to topic db
+        let mut td = if let Some(bytes) = data.topic_db.get(&topic_id)
+            .map_err(|e| ServerErr::from(e))?
+        {
+            let mut td: TopicData = serde_json::from_slice(bytes.as_ref())?;
+            td.add(vec![image_fname.clone()]);
+            td
+        } else {
+            TopicData::new(topic.clone(), None, vec![image_fname.clone()])
+        };
+        save_topic(&data, id, &topic_id, &mut td)?;
+
+        // Keep the search index up to date
+        let caption = td.captions().remove(&image_fname);
+        data.search.index_media(id, &topic, &image_fname, caption, meta, &tags)
+            .and_then(|_| data.search.index_topic(id, &td, &tags))?;
+    }
+
+    Ok(HttpResponse::Ok().body("Success"))
+}
+
+#[get("{id}/{topic}/comments/{media}")]
+async fn get_comments(
+    webpath: web::Path<(String, String, MediaUid)>,
+    query: web::Query<PageQuery>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let page = data.comments.list(&topic_id, &media, query.after, query.limit)?;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+#[post("{id}/{topic}/comments/{media}")]
+async fn add_comment(
+    webpath: web::Path<(String, String, MediaUid)>,
+    payload: web::Json<CommentPayload>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let author = caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let CommentPayload { body, parent } = payload.into_inner();
+    let body = comment_body(body)?;
+    let comment = data.comments.add(&topic_id, &media, author, body, parent)
+        .map_err(|e| ServerErr::bad_request(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+#[post("{id}/{topic}/comments/{media}/{comment}/edit")]
+async fn edit_comment(
+    webpath: web::Path<(String, String, MediaUid, u64)>,
+    payload: web::Json<EditCommentPayload>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media, comment_id) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let comment = find_comment(&data, &topic_id, &media, comment_id)?;
+    // Only the author can change what they said
+    if comment.author != pubkey {
+        return Err(ServerErr::forbidden("Only the author can edit a comment"));
+    }
+    let body = comment_body(payload.into_inner().body)?;
+    let comment = data.comments.edit(&topic_id, &media, comment, body)?;
+
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+#[post("{id}/{topic}/comments/{media}/{comment}/delete")]
+async fn delete_comment(
+    webpath: web::Path<(String, String, MediaUid, u64)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media, comment_id) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let comment = find_comment(&data, &topic_id, &media, comment_id)?;
+    // The topic owner moderates comments on their topic
+    if comment.author != pubkey && id != pubkey {
+        return Err(ServerErr::forbidden("Only the author or topic owner can delete a comment"));
+    }
+    data.comments.delete(&topic_id, &media, comment)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("{id}/{topic}/reactions/{media}")]
+async fn get_reactions(
+    webpath: web::Path<(String, String, MediaUid)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let reactions = data.comments.reactions(&topic_id, &media, Some(&pubkey))?;
+
+    Ok(HttpResponse::Ok().json(reactions))
+}
+
+#[post("{id}/{topic}/reactions/{media}")]
+async fn toggle_reaction(
+    webpath: web::Path<(String, String, MediaUid)>,
+    payload: web::Json<ReactionPayload>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let emoji = payload.into_inner().emoji;
+    if emoji.is_empty()
+        || emoji.chars().count() > MAX_EMOJI_LEN
+        || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(ServerErr::bad_request("Invalid reaction"));
+    }
+    let reacted = data.comments.toggle_reaction(&topic_id, &media, &emoji, &pubkey)?;
+
+    Ok(HttpResponse::Ok().json(reacted))
+}
+
+#[post("{id}/{topic}/favorite/{media}")]
+async fn set_favorite(
+    webpath: web::Path<(String, String, MediaUid)>,
+    payload: web::Json<bool>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let mark = data.favorites.set_favorite(&topic_id, &media, &pubkey, payload.into_inner())?;
+
+    Ok(HttpResponse::Ok().json(mark))
+}
+
+#[post("{id}/{topic}/rating/{media}")]
+async fn set_rating(
+    webpath: web::Path<(String, String, MediaUid)>,
+    payload: web::Json<u8>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic, media) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = readable_topic_with_media(&data, &id, &topic, &media, &caller)?;
+
+    let mark = data.favorites.set_stars(&topic_id, &media, &pubkey, payload.into_inner())
+        .map_err(|e| ServerErr::bad_request(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(mark))
+}
+
+/// Favorites and ratings the session key left in a topic
+#[get("{id}/{topic}/favorites")]
+async fn get_favorites(
+    webpath: web::Path<(String, String)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    let pubkey = caller_key(&caller)?;
+    let topic_id = readable_topic_id(&data, &id, &topic, &caller)?;
+
+    let marks = data.favorites.marks_by(&topic_id, &pubkey)?;
+
+    Ok(HttpResponse::Ok().json(marks))
+}
+
+/// How many contributors favorited and rated each media in a topic
+#[get("{id}/{topic}/favorites/summary")]
+async fn get_favorites_summary(
+    webpath: web::Path<(String, String)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    caller_key(&caller)?;
+    let topic_id = readable_topic_id(&data, &id, &topic, &caller)?;
+
+    let summary = data.favorites.summary(&topic_id)?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[get("/albums/{id}")]
+async fn list_albums(
+    webpath: web::Path<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let id = owner_key(&data, &webpath.into_inner())?;
+    is_verified(&id, &caller)?;
+
+    let albums = data.albums.list(&id)?;
+
+    Ok(HttpResponse::Ok().json(albums))
+}
+
+/// Create or replace the query of a smart album
+#[post("/albums/{id}/{album}")]
+async fn save_album(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<AlbumQuery>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, album) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let mut query = payload.into_inner();
+    query.topics = query.topics.iter().map(|t| normalize_topic(t)).collect();
+    query.tags = query.tags.iter()
+        .map(|t| tag_name(&data, t))
+        .collect::<Result<_>>()?;
+    let album = SmartAlbum {
+        name: normalize_topic(&album),
+        owner: id,
+        query,
+    };
+    data.albums.save(&album)?;
+
+    Ok(HttpResponse::Ok().json(album))
+}
+
+/// Evaluate a smart album into a media list like a regular topic
+#[get("/albums/{id}/{album}")]
+async fn get_album(
+    webpath: web::Path<(String, String)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, album) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let album = find_album(&data, &id, &album)?;
+    let listing = albums::evaluate(&album, &data.search, &data.favorites, &data.topic_db)?;
+
+    Ok(HttpResponse::Ok().json(listing))
+}
+
+#[post("/albums/{id}/{album}/delete")]
+async fn delete_album(
+    webpath: web::Path<(String, String)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, album) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let album = normalize_topic(&album);
+    if !data.albums.remove(&id, &album)? {
+        return Err(ServerErr::not_found(format!("Album {} not found", album)));
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Snapshot the current album contents into a regular topic of the owner.
+/// Media are appended if the topic already exists.
+#[post("/albums/{id}/{album}/materialize")]
+async fn materialize_album(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<String>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, album) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let album = find_album(&data, &id, &album)?;
+    let listing = albums::evaluate(&album, &data.search, &data.favorites, &data.topic_db)?;
+    let uids: Vec<MediaUid> = listing.media.into_iter().map(|m| m.uid).collect();
+
+    let topic = normalize_topic(&payload.into_inner());
+    let topic_id = OwnedTopicId {
+        topic: topic.clone(),
+        owner_id: id.clone(),
+    }.to_string()?;
+    let mut td = match read_topic(&data.topic_db, &topic_id)? {
+        Some(mut td) => {
+            td.add(uids.clone());
+            td
+        }
+        None => TopicData::new(topic.clone(), None, uids.clone()),
+    };
+    save_topic(&data, &id, &topic_id, &mut td)?;
+
+    // Copy the search docs over so the new topic is searchable right away
+    index_media_docs(&data, &id, &td, uids).await?;
+
+    Ok(HttpResponse::Ok().json(td.listing()))
+}
+
+fn find_album(
+    data: &ServerState,
+    id: &str,
+    album: &str,
+) -> Result<SmartAlbum> {
+    let album = normalize_topic(album);
+    data.albums.get(id, &album)?
+        .ok_or_else(|| ServerErr::not_found(format!("Album {} not found", album)))
+}
+
+/// Bytes the next file of an upload may have, the smallest of the file and
+/// request limits, the owner's quota and the disk space above the reserve,
+/// along with the error to stop the upload with when it has more
+fn upload_limit(
+    data: &ServerState,
+    owner: &str,
+    request_left: u64,
+) -> Result<(u64, ServerErr)> {
+    let uploads = &data.args.uploads;
+    let mut limits = vec![
+        (uploads.max_file_bytes,
+            ServerErr::PayloadTooLarge(format!("Files can be at most {} bytes", uploads.max_file_bytes))),
+        (request_left,
+            ServerErr::PayloadTooLarge(format!("Uploads can be at most {} bytes", uploads.max_request_bytes))),
+    ];
+    if let Some(quota) = uploads.quota(owner) {
+        let left = quota.saturating_sub(data.refs.usage(owner)?);
+        limits.push((left,
+            ServerErr::QuotaExceeded(format!("Storage quota of {} bytes is used up", quota))));
+    }
+    let available = fs2::available_space(&data.args.root_dir)?;
+    limits.push((available.saturating_sub(uploads.min_free_bytes),
+        ServerErr::InsufficientStorage("Not enough disk space left for uploads".to_string())));
+
+    let (limit, too_large) = limits.into_iter()
+        .min_by_key(|(bytes, _)| *bytes)
+        .expect("file and request limits are always there");
+    if limit == 0 {
+        return Err(too_large);
+    }
+    Ok((limit, too_large))
+}
+
+/// Write a changed topic, update its directory listing and count the
+/// references to media it added or dropped
+fn save_topic(
+    data: &ServerState,
+    owner: &str,
+    topic_id: &str,
+    td: &mut TopicData,
+) -> Result<()> {
+    let old: HashSet<MediaUid> = read_topic(&data.topic_db, topic_id)?
+        .map(|td| td.list().into_iter().collect())
+        .unwrap_or_default();
+    td.modified = Some(chrono::Utc::now().timestamp());
+    write_topic(&data.topic_db, topic_id, td)?;
+
+    let new: HashSet<MediaUid> = td.list().into_iter().collect();
+    data.refs.incr(owner, new.difference(&old))
+        .and_then(|_| release_media(data, owner, old.difference(&new)))
+        .and_then(|_| data.directory.update(&TopicSummary::new(owner, td)))?;
+    Ok(())
+}
+
+/// Remove a topic from the topic db, dropping its media references
+fn remove_topic_entry(
+    data: &ServerState,
+    owner: &str,
+    topic_id: &str,
+) -> Result<()> {
+    let Some(td) = read_topic(&data.topic_db, topic_id)? else { return Ok(()) };
+    data.topic_db.remove(topic_id)?;
+    release_media(data, owner, td.list().iter())?;
+    Ok(())
+}
+
+/// Drop references to media and delete the files nothing references anymore
+fn release_media<'a>(
+    data: &ServerState,
+    owner: &str,
+    uids: impl IntoIterator<Item = &'a MediaUid>,
+) -> anyhow::Result<()> {
+    let orphaned = data.refs.decr(owner, uids)?;
+    if !orphaned.is_empty() {
+        smol::spawn(delete_originals(data.args.root_dir.clone(), orphaned)).detach();
+    }
+    Ok(())
+}
+
+/// Index media of a topic for search, reading their metadata from disk
+async fn index_media_docs(
+    data: &ServerState,
+    owner: &str,
+    td: &TopicData,
+    uids: Vec<MediaUid>,
+) -> anyhow::Result<()> {
+    let topic_id = OwnedTopicId::new(&td.name, owner).to_string()?;
+    let tags = data.tags.tags_for_topic(&topic_id)?;
+    let mut captions = td.captions();
+    for uid in uids {
+        let path = data.args.root_dir.join(&uid);
+        let Some(mime) = utils::ext(&path).and_then(types::mimes::from_ext) else { continue };
+        let meta = read_media_metadata(path, &mime).await;
+        data.search.index_media(owner, &td.name, &uid, captions.remove(&uid), meta, &tags)?;
+        data.search.set_media_tags(owner, &td.name, &uid, &data.tags.tags_for_media(&topic_id, &uid)?)?;
+    }
+    data.search.index_topic(owner, td, &tags)
+}
+
+/// Move an owned topic to the trash
+#[post("{id}/{topic}/delete")]
+async fn delete_topic(
+    webpath: web::Path<(String, String)>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let topic = normalize_topic(&topic);
+    let topic_id = existing_topic_id(&data, &id, &topic)?;
+    let td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+
+    // The trash entry takes over the media references of the topic
+    let entry = data.trash.put(&id, TrashItem::Topic { data: td })?;
+    data.topic_db.remove(&topic_id)?;
+    data.directory.remove(&id, &topic)
+        .and_then(|_| data.search.remove_topic(&id, &topic))?;
+
+    Ok(HttpResponse::Ok().json(entry))
+}
+
+/// Move a media of an owned topic to the trash
+#[post("{id}/{topic}/delete-media")]
+async fn delete_media(
+    webpath: web::Path<(String, String)>,
+    payload: web::Json<MediaUid>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let (id, topic) = webpath.into_inner();
+    let id = owner_key(&data, &id)?;
+    is_verified(&id, &caller)?;
+
+    let topic = normalize_topic(&topic);
+    let media = payload.into_inner();
+    let topic_id = topic_with_media(&data, &id, &topic, &media)?;
+    let mut td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+
+    // The trash entry holds its own reference so the file outlives the topic's
+    data.refs.incr(&id, [&media])?;
+    let entry = data.trash.put(&id, TrashItem::Media { topic: topic.clone(), uid: media.clone() })?;
+    td.rm(vec![media.clone()]);
+    save_topic(&data, &id, &topic_id, &mut td)?;
+    data.search.remove_media(&id, &topic, &media)?;
+
+    Ok(HttpResponse::Ok().json(entry))
+}
+
+/// Deleted topics and media of the session key, oldest first
+#[get("/trash")]
+async fn get_trash(
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(&caller)?;
+    let entries = data.trash.list(&pubkey)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+fn find_trash_entry(
+    data: &ServerState,
+    owner: &str,
+    id: u64,
+) -> Result<TrashEntry> {
+    data.trash.get(owner, id)?
+        .ok_or_else(|| ServerErr::not_found(format!("Trash entry {} not found", id)))
+}
+
+/// Put a deleted topic or media back. Media go back to the end of their
+/// topic, following the topic if it was renamed since.
+#[post("/trash/{entry}/restore")]
+async fn restore_from_trash(
+    webpath: web::Path<u64>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(&caller)?;
+    let entry = find_trash_entry(&data, &pubkey, webpath.into_inner())?;
+    let td = restore_entry(&data, entry).await?;
+
+    Ok(HttpResponse::Ok().json(TopicSummary::new(&pubkey, &td)))
+}
+
+/// Put the topic or media of a trash entry back and hand its media
+/// references over to the topic, returning the topic it went into
+async fn restore_entry(
+    data: &ServerState,
+    entry: TrashEntry,
+) -> Result<TopicData> {
+    let owner = &entry.owner;
+    let media = entry.media();
+
+    let td = match entry.item {
+        TrashItem::Topic { data: mut td } => {
+            let topic_id = new_topic_id(data, owner, &td.name)?;
+            save_topic(data, owner, &topic_id, &mut td)?;
+            td
+        }
+        TrashItem::Media { topic, uid } => {
+            let topic = data.directory.redirect(owner, &topic)?
+                .unwrap_or(topic);
+            let topic_id = existing_topic_id(data, owner, &topic)
+                .map_err(|_| ServerErr::conflict(format!("Restore topic {} first", topic)))?;
+            let mut td = read_topic(&data.topic_db, &topic_id)?
+                .ok_or_else(|| ServerErr::TopicNotFound(topic.clone()))?;
+            td.add(vec![uid]);
+            save_topic(data, owner, &topic_id, &mut td)?;
+            td
+        }
+    };
+
+    // The topic holds the references now
+    data.trash.take(owner, entry.id)
+        .and_then(|_| release_media(data, owner, media.iter()))?;
+    index_media_docs(data, owner, &td, media).await?;
+    Ok(td)
+}
+
+/// Permanently delete a trash entry now instead of after the retention
+#[post("/trash/{entry}/purge")]
+async fn purge_from_trash(
+    webpath: web::Path<u64>,
+    data: web::Data<ServerState>,
+    caller: Caller,
+) -> Result<HttpResponse> {
+    let pubkey = caller_key(&caller)?;
+    let entry = find_trash_entry(&data, &pubkey, webpath.into_inner())?;
+    purge_entry(&data, entry).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Remove a trash entry for good along with everything kept for it, and
+/// delete originals nothing references anymore
+async fn purge_entry(
+    data: &ServerState,
+    entry: TrashEntry,
+) -> anyhow::Result<()> {
+    if !data.trash.take(&entry.owner, entry.id)? {
+        return Ok(());
+    }
+    let media = entry.media();
+
+    match entry.item {
+        TrashItem::Topic { data: td } => {
+            // A topic made again under the same name keeps the comments, marks and tags
+            let topic_id = OwnedTopicId::new(&td.name, &entry.owner).to_string()?;
+            if !data.topic_db.contains_key(&topic_id)? {
+                data.comments.purge(&topic_id, None)?;
+                data.favorites.purge(&topic_id, None)?;
+                data.tags.purge_media_tags(&topic_id, None)?;
+                for tag in data.tags.tags_for_topic(&topic_id)? {
+                    let index = data.tags.rm_tag(&topic_id, &tag)?;
+                    index_changed(data, &tag, Some(&index), [&topic_id]).await?;
+                }
+            }
+        }
+        TrashItem::Media { topic, uid } => {
+            let topic_id = OwnedTopicId::new(&topic, &entry.owner).to_string()?;
+            let added_back = read_topic(&data.topic_db, &topic_id)?
+                .is_some_and(|td| td.contains(&uid));
+            if !added_back {
+                data.comments.purge(&topic_id, Some(&uid))?;
+                data.favorites.purge(&topic_id, Some(&uid))?;
+                data.tags.purge_media_tags(&topic_id, Some(&uid))?;
+            }
+        }
+    }
+
+    release_media(data, &entry.owner, media.iter())
+}
+
+/// Purge trash entries past the retention once an hour
+fn spawn_trash_purger(data: ServerState) {
+    actix_web::rt::spawn(async move {
+        loop {
+            let cutoff = chrono::Utc::now().timestamp() - data.args.trash_retention_days * DAY_SECS;
+            match data.trash.expired(cutoff) {
+                Ok(entries) => for entry in entries {
+                    log::info!("Purging trash entry {} of {}", entry.id, entry.owner);
+                    if let Err(e) = purge_entry(&data, entry).await {
+                        log::error!("Error purging trash entry: {}", e);
+                    }
+                },
+                Err(e) => log::error!("Error reading trash: {}", e),
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_secs(60 * 60)).await;
+        }
+    });
+}
+
+/// Drop expired server side sessions every hour
+fn spawn_session_pruner(store: SledSessionStore) {
+    actix_web::rt::spawn(async move {
+        loop {
+            match store.remove_expired() {
+                Ok(0) => {}
+                Ok(n) => log::info!("Removed {} expired sessions", n),
+                Err(e) => log::error!("Error removing expired sessions: {}", e),
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_secs(60 * 60)).await;
+        }
+    });
+}
+
+/// Resolve the owner part of a topic address, either a full public key or
+/// a prefix of the key of exactly one owner of the topic
+fn resolve_owner(
+    data: &ServerState,
+    id: &str,
+    topic: &str,
+) -> Result<String> {
+    let topic = normalize_topic(topic);
+    let topic_id = OwnedTopicId::new(&topic, id).to_string()?;
+    if data.topic_db.contains_key(&topic_id)? {
+        return Ok(id.to_string());
+    }
+
+    let mut owners = topic_owners(&data.topic_db, &topic)?;
+    owners.retain(|owner| owner.starts_with(id));
+    match owners.len() {
+        0 => Err(ServerErr::TopicNotFound(topic)),
+        1 => Ok(owners.remove(0)),
+        _ => Err(ServerErr::conflict(format!("Owner {} is ambiguous for topic {}", id, topic))),
+    }
+}
+
+/// Build the topic id and check the topic exists
+fn existing_topic_id(
+    data: &ServerState,
+    id: &str,
+    topic: &str,
+) -> Result<String> {
+    let owner = resolve_owner(data, id, topic)?;
+    Ok(OwnedTopicId::new(topic, &owner).to_string()?)
+}
+
+/// Build the topic id and check the topic exists and contains the media
+fn topic_with_media(
+    data: &ServerState,
+    id: &str,
+    topic: &str,
+    media: &MediaUid,
+) -> Result<String> {
+    let topic = normalize_topic(topic);
+    let owner = resolve_owner(data, id, &topic)?;
+    let topic_id = OwnedTopicId::new(&topic, &owner).to_string()?;
+    let td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic))?;
+    if !td.contains(media) {
+        return Err(ServerErr::not_found(format!("Media {} is not in topic", media)));
+    }
+
+    Ok(topic_id)
+}
+
+/// Anyone can read public topics, only the owner private ones, the same
+/// rule as the image list
+fn check_readable(
+    td: &TopicData,
+    owner: &str,
+    caller: &Caller,
+) -> Result<()> {
+    if !td.public {
+        is_verified(owner, caller)?;
+    }
+    Ok(())
+}
+
+/// Like `existing_topic_id`, but also checks the caller can read the topic
+fn readable_topic_id(
+    data: &ServerState,
+    id: &str,
+    topic: &str,
+    caller: &Caller,
+) -> Result<String> {
+    let topic = normalize_topic(topic);
+    let owner = resolve_owner(data, id, &topic)?;
+    let topic_id = OwnedTopicId::new(&topic, &owner).to_string()?;
+    let td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic))?;
+    check_readable(&td, &owner, caller)?;
+
+    Ok(topic_id)
+}
+
+/// Like `topic_with_media`, but also checks the caller can read the topic
+/// before telling which media it has
+fn readable_topic_with_media(
+    data: &ServerState,
+    id: &str,
+    topic: &str,
+    media: &MediaUid,
+    caller: &Caller,
+) -> Result<String> {
+    let topic = normalize_topic(topic);
+    let owner = resolve_owner(data, id, &topic)?;
+    let topic_id = OwnedTopicId::new(&topic, &owner).to_string()?;
+    let td = read_topic(&data.topic_db, &topic_id)?
+        .ok_or_else(|| ServerErr::TopicNotFound(topic))?;
+    check_readable(&td, &owner, caller)?;
+    if !td.contains(media) {
+        return Err(ServerErr::not_found(format!("Media {} is not in topic", media)));
+    }
+
+    Ok(topic_id)
+}
+
+fn find_comment(
+    data: &ServerState,
+    topic_id: &str,
+    media: &MediaUid,
+    comment_id: u64,
+) -> Result<Comment> {
+    data.comments.get(topic_id, media, comment_id)?
+        .filter(|c| !c.deleted)
+        .ok_or_else(|| ServerErr::not_found(format!("Comment {} not found", comment_id)))
+}
+
+fn comment_body(body: String) -> Result<String> {
+    let body = body.trim().to_string();
+    if body.is_empty() || body.chars().count() > MAX_COMMENT_LEN {
+        return Err(ServerErr::bad_request(
+            format!("Comment must be between 1 and {} characters", MAX_COMMENT_LEN)));
+    }
+    Ok(body)
+}
+
+/// The public key of an `{id}` in a route, which can be a key or an `@handle`
+fn owner_key(
+    data: &ServerState,
+    id: &str,
+) -> Result<String> {
+    let key = data.handles.resolve(id)?
+        .ok_or_else(|| ServerErr::not_found(format!("Handle {} not found", id)))?;
+    Ok(data.keys.account_of(&key)?)
+}
+
+/// Public key the caller authenticated with
+fn caller_key(caller: &Caller) -> Result<String> {
+    caller.key()
+        .ok_or_else(|| ServerErr::unauthenticated("Not verified please authenticate"))
+}
+
+/// Check that the id, owner and caller public key all match
+fn is_verified(
+    id: &str,
+    caller: &Caller,
+) -> Result<()> {
+    // Caller key should match id
+    let pubkey = caller_key(caller)?;
+
+    // check pubkey matches id
+    pubkey.eq(id)
+        .then(|| ())
+        .ok_or_else(|| ServerErr::KeyMismatch("Verified public key does not match provided id".to_string()))
 }
 
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args = config::load()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
     env_logger::init_from_env(env_logger::Env::new(
-            ).default_filter_or("trace actix_web=trace, img=trace"));
+            ).default_filter_or(&args.log_level));
+    if let Some(warning) = session_store::same_site_warning(&args) {
+        log::warn!("{}", warning);
+    }
     /*
     Builder::from_env(env::var("RUST_LOG").unwrap_or_else(|_| "debug".to_string()))
         .format(|buf, record| {
@@ -340,53 +2276,487 @@ async fn main() -> std::io::Result<()> {
         .init();
     */
 
-    let args = Args::from_args();
-    let port = args.port;
+    let db = sled::open(&args.db_path).unwrap();
+    let tree = db.open_tree("topic_db").unwrap();
+    let search = search::SearchIndex::open(&db).unwrap();
+    let comments = comments::Comments::open(&db).unwrap();
+    let favorites = favorites::Favorites::open(&db).unwrap();
+    let albums = albums::SmartAlbums::open(&db).unwrap();
+    let tags = tags::TagDb::open(&db).unwrap();
+    let handles = users::Handles::open(&db).unwrap();
+    let keys = keys::Keys::open(&db).unwrap();
+    let directory = directory::TopicDirectory::open(&db).unwrap();
+    let refs = refs::MediaRefs::open(&db, &args.root_dir).unwrap();
+    let trash = trash::Trash::open(&db).unwrap();
+    let signatures = auth::SeenSignatures::open(&db).unwrap();
+
+    let migration_log = migrations::MigrationLog::open(&db).unwrap();
+
+    // Tag indexes used to live only in indexes/*.json, bring them into sled
+    if !migration_log.has_run("import_tag_indexes")? {
+        if tags.is_empty() {
+            migrations::import_tag_indexes(&args.root_dir, &tags).await
+                .map_err(std::io::Error::other)?;
+        }
+        migration_log.mark_run("import_tag_indexes")?;
+    }
+    if !migration_log.has_run("normalize_tag_names")? {
+        migrations::normalize_tag_names(&tags)
+            .map_err(std::io::Error::other)?;
+        migration_log.mark_run("normalize_tag_names")?;
+    }
+    // Topic tags used to be shared by every owner of a topic name
+    if tags.has_bare_topics().map_err(std::io::Error::other)? {
+        migrations::key_topic_tags(&tree, &tags)
+            .map_err(std::io::Error::other)?;
+    }
 
     // If migrate is true, run migrate function instead of starting server
     if args.migrate {
         //generate_thumbnails(&args.root_dir).await?;
         //update_media_names(&args.root_dir).await?;
+        migrations::build_search_index(&args.root_dir, &tree, &tags, &search).await
+            .map_err(std::io::Error::other)?;
+        migration_log.mark_run("build_search_index")?;
         return Ok(());
     }
-
-    let db = sled::open(&args.db_path).unwrap();
-    let tree = db.open_tree("topic_db").unwrap();
+    // The search index used to be built only with --migrate
+    if !migration_log.has_run("build_search_index")? {
+        migrations::build_search_index(&args.root_dir, &tree, &tags, &search).await
+            .map_err(std::io::Error::other)?;
+        migration_log.mark_run("build_search_index")?;
+    }
+    // References used to be counted without their owners, count them again
+    if refs.is_empty() || !refs.has_owners() {
+        migrations::build_media_refs(&tree, &trash, &refs)
+            .map_err(std::io::Error::other)?;
+    }
+    if directory.is_empty() {
+        migrations::build_topic_directory(&tree, &directory)
+            .map_err(std::io::Error::other)?;
+    }
 
     let thumbnail_sender = thumbnail_generator(&args).await;
     let state = ServerState {
         args: args.clone(),
         topic_db: tree,
+        search,
+        comments,
+        favorites,
+        albums,
+        tags,
+        handles,
+        keys,
+        directory,
+        refs,
+        trash,
+        signatures,
+        auth_attempts: auth::FailedAttempts::default(),
         thumbnail_sender,
     };
+    spawn_trash_purger(state.clone());
 
-    let session_key = session_key::load_or_create_key();
+    let key_path = args.session.key_path.clone()
+        .unwrap_or_else(|| session_key::default_path(&args.root_dir));
+    let session_key = session_key::load_or_create_key(&key_path)?;
+    let session_backend = match args.session.server_store {
+        true => {
+            let store = SledSessionStore::open(&db).unwrap();
+            spawn_session_pruner(store.clone());
+            SessionBackend::Sled(store)
+        }
+        false => SessionBackend::Cookie,
+    };
+    let session_ttl = actix_web::cookie::time::Duration::days(args.session.lifetime_days);
+    let cookie_secure = !args.session.insecure_cookie;
+    let same_site = args.session.same_site.into();
+    let bind = args.bind.clone();
+    let tls_config = match (&args.tls.cert_path, &args.tls.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let reloader = tls::CertReloader::open(cert_path.clone(), key_path.clone())
+                .map(std::sync::Arc::new)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            tls::spawn_reloader(reloader.clone(), &args.tls);
+            Some(tls::server_config(reloader)
+                .map_err(std::io::Error::other)?)
+        }
+        _ => None,
+    };
 
     use actix_web::web::Data;
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(Data::new(state.clone()))
+            .app_data(web::JsonConfig::default().error_handler(extractor_error))
+            .app_data(web::PathConfig::default().error_handler(extractor_error))
+            .app_data(web::QueryConfig::default().error_handler(extractor_error))
+            .wrap(actix_web::middleware::from_fn(auth::verify_signed_request))
             .wrap(actix_web::middleware::Compress::default())
-            .wrap(Cors::permissive())
+            .wrap(cors::policy(&state.args))
             .service(get_index)
+            .service(get_tag_index)
+            .service(get_all_indexes)
+            .service(create_index)
+            .service(rename_index)
+            .service(delete_index)
+            .service(merge_index)
+            .service(set_index_policy)
+            .service(get_search_results)
+            .service(logout)
+            .service(logout_all)
+            .service(list_sessions)
+            .service(revoke_session)
+            .service(register_key)
+            .service(get_profile)
+            .service(rotate_key)
+            .service(list_devices)
+            .service(link_device)
+            .service(remove_device)
+            .service(resolve_topic)
+            .service(list_own_topics)
+            .service(list_public_topics)
+            .service(get_trash)
+            .service(restore_from_trash)
+            .service(purge_from_trash)
+            .service(redirect_topic)
+            // Album routes go before the topic routes they could shadow
+            .service(list_albums)
+            .service(save_album)
+            .service(get_album)
+            .service(delete_album)
+            .service(materialize_album)
             .service(upload_image_by_id)
             .service(get_image_list_by_id)
+            .service(get_topic_history)
+            .service(set_media_caption)
+            .service(set_topic_info)
+            .service(set_topic_visibility)
+            .service(rename_topic)
+            .service(fork_topic)
+            .service(merge_topic)
+            .service(delete_topic)
+            .service(delete_media)
+            .service(get_comments)
+            .service(add_comment)
+            .service(edit_comment)
+            .service(delete_comment)
+            .service(get_reactions)
+            .service(toggle_reaction)
+            .service(set_favorite)
+            .service(set_rating)
+            .service(get_favorites_summary)
+            .service(get_favorites)
             .service(get_tag_list)
+            .service(get_tag_list_by_id)
             .service(add_tag_to_topic)
             .service(rm_tag_from_topic)
+            .service(add_tag_to_topic_path)
+            .service(rm_tag_from_topic_path)
+            .service(get_topic_media_tags)
+            .service(get_media_tags)
+            .service(add_media_tag)
+            .service(rm_media_tag)
             .service(get_image_thumbnail)
             .service(get_image_full)
-            .service(generate_keys)
             .service(generate_challenge)
             .service(authenticate)
+            .service(claim_handle)
+            .service(get_handle_key)
+            .service(get_my_handle)
             .wrap(actix_web::middleware::Logger::default())
-            .wrap(SessionMiddleware::builder(CookieSessionStore::default(), session_key.clone())
-                // todo secure is false for testing locally
-                .cookie_secure(false)
-                .cookie_same_site(actix_web::cookie::SameSite::None)
+            .wrap(SessionMiddleware::builder(session_backend.clone(), session_key.clone())
+                .cookie_secure(cookie_secure)
+                .cookie_same_site(same_site)
+                .cookie_http_only(true)
+                .session_lifecycle(PersistentSession::default().session_ttl(session_ttl))
                 .build())
-    })
-    .bind(format!("localhost:{}", port))?
-    .run()
-    .await
+    });
+    match tls_config {
+        Some(config) => for addr in &bind {
+            log::info!("Listening on https://{}", addr);
+            server = server.bind_rustls_0_23(addr, config.clone())?;
+        },
+        None => for addr in &bind {
+            log::info!("Listening on http://{}", addr);
+            server = server.bind(addr)?;
+        },
+    }
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> ServerState {
+        state_with(Args::default())
+    }
+
+    fn state_with(args: Args) -> ServerState {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ServerState {
+            topic_db: db.open_tree("topic_db").unwrap(),
+            search: search::SearchIndex::open(&db).unwrap(),
+            comments: comments::Comments::open(&db).unwrap(),
+            favorites: favorites::Favorites::open(&db).unwrap(),
+            albums: albums::SmartAlbums::open(&db).unwrap(),
+            tags: tags::TagDb::open(&db).unwrap(),
+            handles: users::Handles::open(&db).unwrap(),
+            keys: keys::Keys::open(&db).unwrap(),
+            directory: directory::TopicDirectory::open(&db).unwrap(),
+            refs: refs::MediaRefs::open(&db, &args.root_dir).unwrap(),
+            trash: trash::Trash::open(&db).unwrap(),
+            signatures: auth::SeenSignatures::open(&db).unwrap(),
+            auth_attempts: auth::FailedAttempts::default(),
+            thumbnail_sender: smol::channel::unbounded().0,
+            args,
+        }
+    }
+
+    fn add_topic(data: &ServerState, owner: &str, topic: &str, public: bool) {
+        let mut td = TopicData::new(topic.to_string(), None, vec!["m1".to_string()]);
+        td.public = public;
+        let topic_id = OwnedTopicId::new(topic, owner).to_string().unwrap();
+        data.topic_db.insert(topic_id, serde_json::to_vec(&td).unwrap()).unwrap();
+    }
+
+    /// Settings with an empty root dir and no disk space reserve
+    fn temp_args() -> Args {
+        let mut args = Args::default();
+        args.root_dir = std::env::temp_dir().join(format!("img-test-{}", rand::random::<u64>()));
+        args.uploads.min_free_bytes = 0;
+        std::fs::create_dir_all(&args.root_dir).unwrap();
+        args
+    }
+
+    /// A multipart body with one jpeg field per file
+    fn multipart(files: &[&[u8]]) -> Multipart {
+        let mut body = vec![];
+        for file in files {
+            body.extend_from_slice(b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.jpeg\"\r\nContent-Type: image/jpeg\r\n\r\n");
+            body.extend_from_slice(file);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--boundary--\r\n");
+        let mut headers = actix_web::http::header::HeaderMap::new();
+        headers.insert(actix_web::http::header::CONTENT_TYPE,
+            actix_web::http::header::HeaderValue::from_static("multipart/form-data; boundary=boundary"));
+        let body = bytes::Bytes::from(body);
+        Multipart::new(&headers, smol::stream::once(Ok::<_, actix_web::error::PayloadError>(body)))
+    }
+
+    fn tmp_files(root_dir: &PathBuf) -> usize {
+        std::fs::read_dir(root_dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "tmp"))
+            .count()
+    }
+
+    fn save_new_topic(data: &ServerState, owner: &str, topic: &str, media: &[&str]) -> String {
+        let topic_id = OwnedTopicId::new(topic, owner).to_string().unwrap();
+        let media = media.iter().map(|uid| uid.to_string()).collect();
+        save_topic(data, owner, &topic_id, &mut TopicData::new(topic.to_string(), None, media)).unwrap();
+        topic_id
+    }
+
+    /// Whether a file is gone once the originals deleted in the background are
+    async fn deleted(path: PathBuf) -> bool {
+        for _ in 0..100 {
+            if !path.exists() {
+                return true;
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    /// Save the next field of an upload within the limits, like upload_image_by_id
+    async fn save_next(data: &ServerState, upload: &mut Multipart, request_left: u64) -> Result<(String, u64)> {
+        let field = upload.try_next().await.unwrap().unwrap();
+        let (limit, too_large) = upload_limit(data, "owner", request_left)?;
+        let (sender, _thumbnails) = smol::channel::unbounded();
+        save_file(&data.args.root_dir, field, "jpeg".to_string(), sender, limit, too_large).await
+    }
+
+    #[test]
+    fn private_topics_are_only_readable_by_their_owner() {
+        let data = state();
+        add_topic(&data, "owner", "trip", false);
+
+        assert!(readable_topic_id(&data, "owner", "trip", &Caller::signed_in("owner")).is_ok());
+        let other = readable_topic_id(&data, "owner", "trip", &Caller::signed_in("other"));
+        assert!(matches!(other, Err(ServerErr::KeyMismatch(_))));
+        let anonymous = readable_topic_id(&data, "owner", "trip", &Caller::default());
+        assert!(matches!(anonymous, Err(ServerErr::Unauthenticated(_))));
+        let missing = readable_topic_id(&data, "owner", "cabin", &Caller::signed_in("owner"));
+        assert!(matches!(missing, Err(ServerErr::TopicNotFound(_))));
+    }
+
+    #[test]
+    fn public_topics_are_readable_by_everyone() {
+        let data = state();
+        add_topic(&data, "owner", "trip", true);
+
+        assert!(readable_topic_id(&data, "owner", "trip", &Caller::signed_in("other")).is_ok());
+        assert!(readable_topic_id(&data, "owner", "trip", &Caller::default()).is_ok());
+    }
+
+    #[test]
+    fn media_of_unreadable_topics_is_not_revealed() {
+        let data = state();
+        add_topic(&data, "owner", "trip", false);
+        let m1 = "m1".to_string();
+        let m2 = "m2".to_string();
+
+        assert!(readable_topic_with_media(&data, "owner", "trip", &m1, &Caller::signed_in("owner")).is_ok());
+        let missing = readable_topic_with_media(&data, "owner", "trip", &m2, &Caller::signed_in("owner"));
+        assert!(matches!(missing, Err(ServerErr::NotFound(_))));
+        for media in [&m1, &m2] {
+            let other = readable_topic_with_media(&data, "owner", "trip", media, &Caller::signed_in("other"));
+            assert!(matches!(other, Err(ServerErr::KeyMismatch(_))));
+        }
+    }
+
+    #[test]
+    fn topics_are_only_writable_by_their_owner() {
+        let data = state();
+        add_topic(&data, "owner", "trip", true);
+        add_topic(&data, "other", "cabin", false);
+
+        assert!(can_write_topic(&data, "trip", "owner").unwrap());
+        assert!(!can_write_topic(&data, "trip", "other").unwrap());
+        assert!(!can_write_topic(&data, "cabin", "owner").unwrap());
+    }
+
+    #[actix_web::test]
+    async fn child_tags_follow_the_parent_policy() {
+        let data = state();
+        add_topic(&data, "other", "cabin", true);
+        data.tags.create("trips", HashSet::new(), "owner").unwrap();
+        data.tags.set_policy("trips", types::topic::IndexPolicy::Closed, HashSet::new()).unwrap();
+
+        let denied = add_tag(&data, "cabin", "trips/x", &Caller::signed_in("other")).await;
+        assert!(matches!(denied, Err(ServerErr::Forbidden(_))));
+        assert!(!can_add_to_tag(&data, "trips/x/y", "other").unwrap());
+        assert!(can_add_to_tag(&data, "trips/x", "owner").unwrap());
+        assert!(can_add_to_tag(&data, "cabins/x", "other").unwrap());
+    }
+
+    #[actix_web::test]
+    async fn files_over_the_file_limit_are_refused() {
+        let mut args = temp_args();
+        args.uploads.max_file_bytes = 4;
+        let data = state_with(args);
+
+        let saved = save_next(&data, &mut multipart(&[b"hello"]), 100).await;
+        assert!(matches!(saved, Err(ServerErr::PayloadTooLarge(msg)) if msg.starts_with("Files")));
+        assert_eq!(tmp_files(&data.args.root_dir), 0);
+        std::fs::remove_dir_all(&data.args.root_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn files_over_the_quota_are_refused() {
+        let mut args = temp_args();
+        args.uploads.quota_bytes = 8;
+        let data = state_with(args);
+        let uid = "used.jpeg".to_string();
+        std::fs::write(data.args.root_dir.join(&uid), b"123456").unwrap();
+        data.refs.incr("owner", [&uid]).unwrap();
+
+        let saved = save_next(&data, &mut multipart(&[b"hello"]), 100).await;
+        assert!(matches!(saved, Err(ServerErr::QuotaExceeded(_))));
+        assert_eq!(tmp_files(&data.args.root_dir), 0);
+
+        // Nothing is read once the quota is used up
+        std::fs::write(data.args.root_dir.join("more.jpeg"), b"12").unwrap();
+        data.refs.incr("owner", [&"more.jpeg".to_string()]).unwrap();
+        assert!(matches!(upload_limit(&data, "owner", 100), Err(ServerErr::QuotaExceeded(_))));
+        std::fs::remove_dir_all(&data.args.root_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn restored_topics_take_back_their_references() {
+        let data = state_with(temp_args());
+        let root = data.args.root_dir.clone();
+        std::fs::write(root.join("a.jpeg"), b"abc").unwrap();
+        let topic_id = save_new_topic(&data, "owner", "trip", &["a.jpeg"]);
+
+        // Deleting hands the references to the trash entry, like delete_topic
+        let td = read_topic(&data.topic_db, &topic_id).unwrap().unwrap();
+        let entry = data.trash.put("owner", TrashItem::Topic { data: td }).unwrap();
+        data.topic_db.remove(&topic_id).unwrap();
+        restore_entry(&data, entry).await.unwrap();
+        assert!(data.topic_db.contains_key(&topic_id).unwrap());
+        assert!(data.trash.list("owner").unwrap().is_empty());
+        assert_eq!(data.refs.usage("owner").unwrap(), 3);
+
+        // The restored topic holds the one reference left
+        remove_topic_entry(&data, "owner", &topic_id).unwrap();
+        assert!(deleted(root.join("a.jpeg")).await);
+        assert!(!data.refs.has_media("owner"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn restored_media_go_back_to_their_topic() {
+        let data = state_with(temp_args());
+        let root = data.args.root_dir.clone();
+        std::fs::write(root.join("a.jpeg"), b"abc").unwrap();
+        let topic_id = save_new_topic(&data, "owner", "trip", &["a.jpeg"]);
+        let uid = "a.jpeg".to_string();
+
+        // Like delete_media, the entry holds its own reference
+        data.refs.incr("owner", [&uid]).unwrap();
+        let entry = data.trash.put("owner", TrashItem::Media { topic: "trip".to_string(), uid: uid.clone() }).unwrap();
+        let mut td = read_topic(&data.topic_db, &topic_id).unwrap().unwrap();
+        td.rm(vec![uid.clone()]);
+        save_topic(&data, "owner", &topic_id, &mut td).unwrap();
+        assert!(root.join(&uid).exists());
+
+        let td = restore_entry(&data, entry).await.unwrap();
+        assert!(td.contains(&uid));
+        remove_topic_entry(&data, "owner", &topic_id).unwrap();
+        assert!(deleted(root.join(&uid)).await);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn purging_deletes_only_unreferenced_media() {
+        let data = state_with(temp_args());
+        let root = data.args.root_dir.clone();
+        for uid in ["a.jpeg", "b.jpeg"] {
+            std::fs::write(root.join(uid), uid).unwrap();
+        }
+        let topic_id = save_new_topic(&data, "owner", "trip", &["a.jpeg", "b.jpeg"]);
+        let shared_id = save_new_topic(&data, "other", "cabin", &["b.jpeg"]);
+
+        let td = read_topic(&data.topic_db, &topic_id).unwrap().unwrap();
+        let entry = data.trash.put("owner", TrashItem::Topic { data: td }).unwrap();
+        data.topic_db.remove(&topic_id).unwrap();
+        purge_entry(&data, entry).await.unwrap();
+        assert!(deleted(root.join("a.jpeg")).await);
+        assert!(root.join("b.jpeg").exists());
+        assert!(!data.refs.has_media("owner"));
+
+        remove_topic_entry(&data, "other", &shared_id).unwrap();
+        assert!(deleted(root.join("b.jpeg")).await);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn uploads_share_the_request_limit_across_fields() {
+        let mut args = temp_args();
+        args.uploads.max_file_bytes = 8;
+        args.uploads.max_request_bytes = 10;
+        let data = state_with(args);
+        let mut upload = multipart(&[b"first!", b"second"]);
+
+        let mut request_left = data.args.uploads.max_request_bytes;
+        let (fname, size) = save_next(&data, &mut upload, request_left).await.unwrap();
+        assert!(data.args.root_dir.join(fname).exists());
+        request_left -= size;
+        let saved = save_next(&data, &mut upload, request_left).await;
+        assert!(matches!(saved, Err(ServerErr::PayloadTooLarge(msg)) if msg.starts_with("Uploads")));
+        assert_eq!(tmp_files(&data.args.root_dir), 0);
+        std::fs::remove_dir_all(&data.args.root_dir).unwrap();
+    }
 }
@@ -1,8 +1,42 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use smol::io::AsyncWriteExt;
 use smol::stream::StreamExt;
 
-use crate::utils::{get_uid, get_topic_ids, serialize_topics, get_media_paths};
+use crate::utils::{
+    get_uid,
+    get_topic_ids,
+    serialize_topics,
+    get_media_paths,
+    get_index_paths,
+    read_media_metadata,
+    topic_owners,
+};
+use crate::types::topic::{Index, OwnedTopicId, TopicData};
+use crate::types::mimes::from_ext;
+use crate::search::SearchIndex;
+use crate::tags::{normalize_tag, TagDb};
+use crate::directory::{TopicDirectory, TopicSummary};
+use crate::refs::MediaRefs;
+use crate::trash::Trash;
+
+/// Names of the one-time migrations that already ran on the db
+pub struct MigrationLog(sled::Tree);
+
+impl MigrationLog {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self(db.open_tree("migrations")?))
+    }
+
+    pub fn has_run(&self, name: &str) -> sled::Result<bool> {
+        self.0.contains_key(name.as_bytes())
+    }
+
+    pub fn mark_run(&self, name: &str) -> sled::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        self.0.insert(name.as_bytes(), &now.to_be_bytes())?;
+        Ok(())
+    }
+}
 
 pub async fn update_media_names(root_dir: &PathBuf) -> anyhow::Result<()> {
     let json_files = get_topic_ids(root_dir).await?;
@@ -92,3 +126,196 @@ pub async fn generate_thumbnails(root_dir: &PathBuf) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Import the tag indexes from root/indexes/*.json into sled
+pub async fn import_tag_indexes(
+    root_dir: &PathBuf,
+    tags: &TagDb,
+) -> anyhow::Result<()> {
+    for index_path in get_index_paths(root_dir).await? {
+        let raw_json = smol::fs::read(&index_path).await?;
+        let index: Index = serde_json::from_slice(&raw_json)?;
+        log::info!("Importing tag index {}", index.name);
+        tags.import(&index)?;
+    }
+
+    Ok(())
+}
+
+/// Rename tags created before tag names were normalized, merging them into
+/// an existing tag with the normalized name
+pub fn normalize_tag_names(tags: &TagDb) -> anyhow::Result<()> {
+    for index in tags.all()? {
+        let name = normalize_tag(&index.name);
+        if name == index.name || name.is_empty() {
+            continue;
+        }
+        log::info!("Normalizing tag {} to {}", index.name, name);
+        if tags.get(&name)?.is_some() {
+            tags.merge(&index.name, &name)?;
+        } else {
+            tags.rename(&index.name, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Tag every owned topic of a name instead of the bare name, which any
+/// owner of the name could tag or untag for everyone
+pub fn key_topic_tags(topic_db: &sled::Tree, tags: &TagDb) -> anyhow::Result<()> {
+    tags.rekey_topics(|topic| {
+        log::info!("Tagging each owner's topic {}", topic);
+        topic_owners(topic_db, topic)?.iter()
+            .map(|owner| Ok(OwnedTopicId::new(topic, owner).to_string()?))
+            .collect()
+    })
+}
+
+/// Rebuild the search index from the topic db and all tag indexes
+pub async fn build_search_index(
+    root_dir: &Path,
+    topic_db: &sled::Tree,
+    tags: &TagDb,
+    search: &SearchIndex,
+) -> anyhow::Result<()> {
+    for index in tags.all()? {
+        search.index_tag(&index.name)?;
+    }
+
+    for entry in topic_db.iter() {
+        let (key, bytes) = entry?;
+        let topic_id: OwnedTopicId = serde_json::from_slice(&key)?;
+        let td: TopicData = serde_json::from_slice(&bytes)?;
+        log::info!("Indexing topic {} of {}", td.name, topic_id.owner_id);
+
+        let topic_tags = tags.tags_for_topic(std::str::from_utf8(&key)?)?;
+        search.index_topic(&topic_id.owner_id, &td, &topic_tags)?;
+
+        let mut captions = td.captions();
+        for uid in td.list() {
+            let path = root_dir.join(&uid);
+            let Some(mime) = crate::utils::ext(&path).and_then(from_ext) else {
+                log::warn!("Skipping media with unknown type {}", uid);
+                continue;
+            };
+            let meta = read_media_metadata(path, &mime).await;
+            let caption = captions.remove(&uid);
+            search.index_media(&topic_id.owner_id, &td.name, &uid, caption, meta, &topic_tags)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// List every topic of the topic db in the topic directory
+pub fn build_topic_directory(
+    topic_db: &sled::Tree,
+    directory: &TopicDirectory,
+) -> anyhow::Result<()> {
+    for entry in topic_db.iter() {
+        let (key, bytes) = entry?;
+        let topic_id: OwnedTopicId = serde_json::from_slice(&key)?;
+        let td: TopicData = serde_json::from_slice(&bytes)?;
+        directory.update(&TopicSummary::new(&topic_id.owner_id, &td))?;
+    }
+
+    Ok(())
+}
+
+/// Count the topics and trash entries referencing each media file, per owner
+pub fn build_media_refs(
+    topic_db: &sled::Tree,
+    trash: &Trash,
+    refs: &MediaRefs,
+) -> anyhow::Result<()> {
+    refs.clear()?;
+    for entry in topic_db.iter() {
+        let (key, bytes) = entry?;
+        let topic_id: OwnedTopicId = serde_json::from_slice(&key)?;
+        let td: TopicData = serde_json::from_slice(&bytes)?;
+        refs.incr(&topic_id.owner_id, td.list().iter())?;
+    }
+    for entry in trash.all()? {
+        refs.incr(&entry.owner, entry.media().iter())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+    use super::*;
+
+    /// Every index with its topics
+    fn snapshot(tags: &TagDb) -> BTreeMap<String, HashSet<String>> {
+        tags.all().unwrap().into_iter().map(|index| (index.name, index.topics)).collect()
+    }
+
+    fn write_index(dir: &PathBuf, name: &str, topics: &[&str]) {
+        let mut index = Index::new(name.to_string(), Some("k1".to_string()));
+        index.topics = topics.iter().map(|t| t.to_string()).collect();
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(format!("{}.json", rand::random::<u64>())), serde_json::to_vec(&index).unwrap()).unwrap();
+    }
+
+    fn topic_id(topic: &str, owner: &str) -> String {
+        OwnedTopicId::new(topic, owner).to_string().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn old_indexes_import_under_normalized_owned_topics() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tags = TagDb::open(&db).unwrap();
+        let topic_db = db.open_tree("topic_db").unwrap();
+        let root_dir = std::env::temp_dir().join(format!("img-test-{}", rand::random::<u64>()));
+        let indexes = root_dir.join("indexes");
+        write_index(&indexes, "Trips", &["cabin"]);
+        write_index(&indexes, "trips", &["lake"]);
+        write_index(&indexes.join("trips"), "Trips/Alaska", &["cabin", "gone"]);
+        for (topic, owner) in [("cabin", "k1"), ("cabin", "k2"), ("lake", "k1")] {
+            let td = TopicData::new(topic.to_string(), None, vec![]);
+            crate::utils::write_topic(&topic_db, &topic_id(topic, owner), &td).unwrap();
+        }
+
+        import_tag_indexes(&root_dir, &tags).await.unwrap();
+        let imported = snapshot(&tags);
+        assert_eq!(imported.keys().collect::<Vec<_>>(), ["trips", "trips/alaska"]);
+        assert_eq!(imported["trips"], HashSet::from(["cabin".to_string(), "lake".to_string()]));
+        import_tag_indexes(&root_dir, &tags).await.unwrap();
+        assert_eq!(snapshot(&tags), imported);
+
+        // Each owner of a name gets its own topic tagged, unowned names are dropped
+        assert!(tags.has_bare_topics().unwrap());
+        key_topic_tags(&topic_db, &tags).unwrap();
+        let keyed = snapshot(&tags);
+        assert_eq!(keyed["trips"], HashSet::from([
+            topic_id("cabin", "k1"), topic_id("cabin", "k2"), topic_id("lake", "k1")]));
+        assert_eq!(keyed["trips/alaska"], HashSet::from([topic_id("cabin", "k1"), topic_id("cabin", "k2")]));
+        assert!(tags.tags_for_topic(&topic_id("cabin", "k2")).unwrap().contains("trips/alaska"));
+        assert!(!tags.has_bare_topics().unwrap());
+        key_topic_tags(&topic_db, &tags).unwrap();
+        assert_eq!(snapshot(&tags), keyed);
+
+        std::fs::remove_dir_all(&root_dir).unwrap();
+    }
+
+    #[test]
+    fn mixed_case_tags_are_renamed_or_merged() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tags = TagDb::open(&db).unwrap();
+        let ids = |topics: &[&str]| topics.iter().map(|t| topic_id(t, "k1")).collect::<HashSet<_>>();
+        tags.create("trips", ids(&["lake"]), "k1").unwrap();
+        tags.create("Trips", ids(&["cabin"]), "k1").unwrap();
+        tags.create("Lakes/North Shore", ids(&["lake"]), "k1").unwrap();
+
+        normalize_tag_names(&tags).unwrap();
+        let normalized = snapshot(&tags);
+        assert_eq!(normalized.keys().collect::<Vec<_>>(), ["lakes/north-shore", "trips"]);
+        assert_eq!(normalized["trips"], ids(&["cabin", "lake"]));
+        assert_eq!(tags.resolve("Trips").unwrap(), "trips");
+        normalize_tag_names(&tags).unwrap();
+        assert_eq!(snapshot(&tags), normalized);
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+use crate::types::topic::MediaUid;
+
+const SEP: char = '\0';
+
+/// How many topics and trash entries reference each original media file.
+/// Keyed by media uid with a big endian count, media without references
+/// have no entry and their files can be deleted.
+///
+/// The references are also counted per owner under `{owner}\0{uid}`, along
+/// with the size of the file when the owner first referenced it, which is
+/// what storage quotas are charged from.
+#[derive(Clone)]
+pub struct MediaRefs {
+    refs: sled::Tree,
+    owned: sled::Tree,
+    root_dir: PathBuf,
+}
+
+fn decode(bytes: Option<&[u8]>) -> u64 {
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0)
+}
+
+/// Count and size of an owner's references to a media
+fn decode_owned(bytes: Option<&[u8]>) -> (u64, u64) {
+    match bytes {
+        Some(b) if b.len() == 16 => (decode(Some(&b[..8])), decode(Some(&b[8..]))),
+        _ => (0, 0),
+    }
+}
+
+fn owned_key(owner: &str, uid: &MediaUid) -> Vec<u8> {
+    format!("{owner}{SEP}{uid}").into_bytes()
+}
+
+impl MediaRefs {
+    pub fn open(db: &sled::Db, root_dir: &Path) -> sled::Result<Self> {
+        Ok(Self {
+            refs: db.open_tree("media_refs")?,
+            owned: db.open_tree("owner_media_refs")?,
+            root_dir: root_dir.to_path_buf(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.refs.is_empty()
+    }
+
+    /// Whether references are counted per owner, they weren't at first
+    pub fn has_owners(&self) -> bool {
+        !self.owned.is_empty()
+    }
+
+    /// Forget every reference, before counting them again
+    pub fn clear(&self) -> sled::Result<()> {
+        self.refs.clear()?;
+        self.owned.clear()
+    }
+
+    fn update(&self, uid: &MediaUid, f: impl Fn(u64) -> u64) -> anyhow::Result<u64> {
+        let new = self.refs.update_and_fetch(uid.as_bytes(), |old| {
+            let count = f(decode(old));
+            (count > 0).then(|| count.to_be_bytes().to_vec())
+        })?;
+        Ok(decode(new.as_deref()))
+    }
+
+    fn update_owned(&self, owner: &str, uid: &MediaUid, f: impl Fn(u64) -> u64) -> anyhow::Result<()> {
+        // Read before the update closure, which sled may run more than once
+        let size = std::fs::metadata(self.root_dir.join(uid)).map(|m| m.len()).unwrap_or(0);
+        self.owned.update_and_fetch(owned_key(owner, uid), |old| {
+            let (count, old_size) = decode_owned(old);
+            let count = f(count);
+            let size = if old.is_some() { old_size } else { size };
+            (count > 0).then(|| [count.to_be_bytes(), size.to_be_bytes()].concat())
+        })?;
+        Ok(())
+    }
+
+    pub fn incr<'a>(
+        &self,
+        owner: &str,
+        uids: impl IntoIterator<Item = &'a MediaUid>,
+    ) -> anyhow::Result<()> {
+        for uid in uids {
+            self.update(uid, |n| n + 1)?;
+            self.update_owned(owner, uid, |n| n + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Drop a reference to each media, returning those nothing references anymore
+    pub fn decr<'a>(
+        &self,
+        owner: &str,
+        uids: impl IntoIterator<Item = &'a MediaUid>,
+    ) -> anyhow::Result<Vec<MediaUid>> {
+        let mut orphaned = vec![];
+        for uid in uids {
+            self.update_owned(owner, uid, |n| n.saturating_sub(1))?;
+            if self.update(uid, |n| n.saturating_sub(1))? == 0 {
+                orphaned.push(uid.clone());
+            }
+        }
+        Ok(orphaned)
+    }
+
+    /// Whether the owner references any media
+    pub fn has_media(&self, owner: &str) -> bool {
+        self.owned.scan_prefix(format!("{owner}{SEP}").as_bytes()).next().is_some()
+    }
+
+    /// Bytes of the media an owner references, each file counted once
+    pub fn usage(&self, owner: &str) -> anyhow::Result<u64> {
+        let mut total = 0;
+        for bytes in self.owned.scan_prefix(format!("{owner}{SEP}").as_bytes()).values() {
+            total += decode_owned(Some(&bytes?)).1;
+        }
+        Ok(total)
+    }
+}
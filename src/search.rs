@@ -0,0 +1,458 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+use crate::db::{abort, tx_err};
+use crate::types::topic::{MediaUid, TopicData};
+use crate::tags::with_ancestors;
+
+/// Separates the parts of a search key, never part of a token
+const SEP: char = '\0';
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum DocKind {
+    Tag,
+    Topic,
+    Media,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Facets {
+    pub tags: Vec<String>,
+    /// Tags of a single media on top of the tags of its topic
+    #[serde(default)]
+    pub media_tags: Vec<String>,
+    pub camera: Option<String>,
+    pub year: Option<i32>,
+    pub media_type: Option<String>,
+}
+
+/// Metadata read out of a media file when it is indexed
+#[derive(Debug, Default)]
+pub struct MediaMetadata {
+    pub camera: Option<String>,
+    pub year: Option<i32>,
+    pub media_type: Option<String>,
+    /// Other EXIF values worth matching on (lens, artist, description...)
+    pub fields: Vec<String>,
+}
+
+/// A searchable document, either a topic, a tag index or a single media item in a topic
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchDoc {
+    pub kind: DocKind,
+    /// Topic name, tag name or media uid
+    pub name: String,
+    pub topic: Option<String>,
+    /// Public key of the topic owner, docs without an owner are visible to everyone
+    pub owner: Option<String>,
+    /// Media caption, or the title and description of a topic
+    #[serde(default)]
+    pub caption: Option<String>,
+    /// Extra text tokenized into the index besides the name
+    #[serde(default)]
+    pub text: Vec<String>,
+    pub facets: Facets,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    pub kind: Option<DocKind>,
+    pub tag: Option<String>,
+    pub camera: Option<String>,
+    pub year: Option<i32>,
+    pub media_type: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Default)]
+pub struct FacetCounts {
+    pub tag: BTreeMap<String, usize>,
+    pub camera: BTreeMap<String, usize>,
+    pub year: BTreeMap<i32, usize>,
+    pub media_type: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    pub total: usize,
+    pub hits: Vec<SearchDoc>,
+    pub facets: FacetCounts,
+}
+
+/// Inverted index over topics, tags and media kept in two sled trees.
+/// `search_docs` maps a doc key to the json SearchDoc and `search_terms`
+/// holds one `{term}\0{doc key}` entry per token of each doc.
+#[derive(Clone)]
+pub struct SearchIndex {
+    docs: sled::Tree,
+    terms: sled::Tree,
+}
+
+/// Lowercase and split text into alphanumeric tokens
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+fn topic_key(topic: &str, owner: &str) -> String {
+    format!("topic{SEP}{topic}{SEP}{owner}")
+}
+
+fn media_key(topic: &str, owner: &str, uid: &str) -> String {
+    format!("media{SEP}{topic}{SEP}{owner}{SEP}{uid}")
+}
+
+fn tag_key(tag: &str) -> String {
+    format!("tag{SEP}{tag}")
+}
+
+impl Facets {
+    /// Topic and media level tags, which may overlap
+    pub fn all_tags(&self) -> impl Iterator<Item = &String> {
+        self.tags.iter().chain(self.media_tags.iter())
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.all_tags().any(|t| t == tag)
+    }
+}
+
+impl SearchDoc {
+    fn tokens(&self) -> BTreeSet<String> {
+        let mut tokens: BTreeSet<String> = tokenize(&self.name).into_iter().collect();
+        let text = self.text.iter()
+            .chain(self.caption.iter())
+            .chain(self.facets.all_tags())
+            .chain(self.facets.camera.iter())
+            .chain(self.facets.media_type.iter());
+        for t in text {
+            tokens.extend(tokenize(t));
+        }
+        if let Some(year) = self.facets.year {
+            tokens.insert(year.to_string());
+        }
+        tokens
+    }
+
+    fn matches(&self, query: &SearchQuery) -> bool {
+        let f = &self.facets;
+        query.kind.is_none_or(|k| k == self.kind)
+            && query.tag.as_ref().is_none_or(|t| f.has_tag(t))
+            && query.camera.as_ref().is_none_or(|c| f.camera.as_ref() == Some(c))
+            && query.year.is_none_or(|y| f.year == Some(y))
+            && query.media_type.as_ref().is_none_or(|m| f.media_type.as_ref() == Some(m))
+    }
+}
+
+impl FacetCounts {
+    fn count(&mut self, doc: &SearchDoc) {
+        let f = &doc.facets;
+        for tag in f.all_tags().collect::<BTreeSet<_>>() {
+            *self.tag.entry(tag.clone()).or_default() += 1;
+        }
+        if let Some(camera) = &f.camera {
+            *self.camera.entry(camera.clone()).or_default() += 1;
+        }
+        if let Some(year) = f.year {
+            *self.year.entry(year).or_default() += 1;
+        }
+        if let Some(media_type) = &f.media_type {
+            *self.media_type.entry(media_type.clone()).or_default() += 1;
+        }
+    }
+}
+
+impl SearchIndex {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            docs: db.open_tree("search_docs")?,
+            terms: db.open_tree("search_terms")?,
+        })
+    }
+
+    /// Insert or replace a doc along with its terms, or remove it when doc is None
+    fn put(&self, key: &str, doc: Option<&SearchDoc>) -> anyhow::Result<()> {
+        let new_bytes = doc.map(serde_json::to_vec).transpose()?;
+        let new_tokens = doc.map(|d| d.tokens()).unwrap_or_default();
+
+        (&self.docs, &self.terms).transaction(|(docs, terms)| {
+            // Drop the terms of the previous version of the doc
+            if let Some(old) = docs.get(key)? {
+                let old: SearchDoc = serde_json::from_slice(&old)
+                    .map_err(|e| abort(e.into()))?;
+                for token in old.tokens() {
+                    terms.remove(format!("{token}{SEP}{key}").as_bytes())?;
+                }
+            }
+
+            match &new_bytes {
+                Some(bytes) => {
+                    docs.insert(key.as_bytes(), bytes.as_slice())?;
+                    for token in new_tokens.iter() {
+                        terms.insert(format!("{token}{SEP}{key}").as_bytes(), &[])?;
+                    }
+                }
+                None => {
+                    docs.remove(key.as_bytes())?;
+                }
+            }
+            Ok(())
+        }).map_err(tx_err)
+    }
+
+    fn get(&self, key: &[u8]) -> anyhow::Result<Option<SearchDoc>> {
+        self.docs.get(key)?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    pub fn index_topic(
+        &self,
+        owner: &str,
+        td: &TopicData,
+        tags: &HashSet<String>,
+    ) -> anyhow::Result<()> {
+        let info = td.info();
+        let caption = [info.title, info.description].into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("\n");
+        let doc = SearchDoc {
+            kind: DocKind::Topic,
+            name: td.name.clone(),
+            topic: Some(td.name.clone()),
+            owner: Some(owner.to_string()),
+            caption: (!caption.is_empty()).then_some(caption),
+            text: vec![],
+            facets: Facets {
+                tags: with_ancestors(tags).into_iter().collect(),
+                ..Default::default()
+            },
+        };
+        self.put(&topic_key(&td.name, owner), Some(&doc))
+    }
+
+    pub fn index_media(
+        &self,
+        owner: &str,
+        topic: &str,
+        uid: &MediaUid,
+        caption: Option<String>,
+        meta: MediaMetadata,
+        tags: &HashSet<String>,
+    ) -> anyhow::Result<()> {
+        // Media tags are set separately, keep them when reindexing
+        let key = media_key(topic, owner, uid);
+        let media_tags = self.get(key.as_bytes())?
+            .map(|doc| doc.facets.media_tags)
+            .unwrap_or_default();
+        let doc = SearchDoc {
+            kind: DocKind::Media,
+            name: uid.clone(),
+            topic: Some(topic.to_string()),
+            owner: Some(owner.to_string()),
+            caption,
+            text: meta.fields,
+            facets: Facets {
+                tags: with_ancestors(tags).into_iter().collect(),
+                media_tags,
+                camera: meta.camera,
+                year: meta.year,
+                media_type: meta.media_type,
+            },
+        };
+        self.put(&key, Some(&doc))
+    }
+
+    /// Index a tag by its name only, its topics may not be visible to everyone
+    pub fn index_tag(&self, tag: &str) -> anyhow::Result<()> {
+        let doc = SearchDoc {
+            kind: DocKind::Tag,
+            name: tag.to_string(),
+            topic: None,
+            owner: None,
+            caption: None,
+            text: vec![],
+            facets: Facets::default(),
+        };
+        self.put(&tag_key(tag), Some(&doc))
+    }
+
+    pub fn remove_tag(&self, tag: &str) -> anyhow::Result<()> {
+        self.put(&tag_key(tag), None)
+    }
+
+    /// Update the caption of an already indexed media
+    pub fn set_caption(
+        &self,
+        owner: &str,
+        topic: &str,
+        uid: &MediaUid,
+        caption: Option<String>,
+    ) -> anyhow::Result<()> {
+        let key = media_key(topic, owner, uid);
+        if let Some(mut doc) = self.get(key.as_bytes())? {
+            doc.caption = caption;
+            self.put(&key, Some(&doc))?;
+        }
+        Ok(())
+    }
+
+    /// Update the media tag facet of an already indexed media
+    pub fn set_media_tags(
+        &self,
+        owner: &str,
+        topic: &str,
+        uid: &MediaUid,
+        tags: &HashSet<String>,
+    ) -> anyhow::Result<()> {
+        let key = media_key(topic, owner, uid);
+        if let Some(mut doc) = self.get(key.as_bytes())? {
+            doc.facets.media_tags = with_ancestors(tags).into_iter().collect();
+            self.put(&key, Some(&doc))?;
+        }
+        Ok(())
+    }
+
+    /// Update the tag facet of an owned topic and all its media, with the
+    /// parents of each tag so filtering on a parent matches
+    pub fn set_topic_tags(
+        &self,
+        owner: &str,
+        topic: &str,
+        tags: &HashSet<String>,
+    ) -> anyhow::Result<()> {
+        let mut keys = vec![topic_key(topic, owner)];
+        let prefix = format!("media{SEP}{topic}{SEP}{owner}{SEP}");
+        for key in self.docs.scan_prefix(prefix.as_bytes()).keys() {
+            keys.push(String::from_utf8(key?.to_vec())?);
+        }
+        for key in keys {
+            if let Some(mut doc) = self.get(key.as_bytes())? {
+                doc.facets.tags = with_ancestors(tags).into_iter().collect();
+                self.put(&key, Some(&doc))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the docs of an owned topic and its media to another owned topic,
+    /// or copy them when `keep` is set
+    pub fn move_topic(
+        &self,
+        from_owner: &str,
+        from_topic: &str,
+        to_owner: &str,
+        to_topic: &str,
+        keep: bool,
+    ) -> anyhow::Result<()> {
+        let mut keys = vec![topic_key(from_topic, from_owner)];
+        let prefix = format!("media{SEP}{from_topic}{SEP}{from_owner}{SEP}");
+        for key in self.docs.scan_prefix(prefix.as_bytes()).keys() {
+            keys.push(String::from_utf8(key?.to_vec())?);
+        }
+
+        for key in keys {
+            let Some(mut doc) = self.get(key.as_bytes())? else { continue };
+            let new_key = match doc.kind {
+                DocKind::Topic => {
+                    doc.name = to_topic.to_string();
+                    topic_key(to_topic, to_owner)
+                }
+                _ => media_key(to_topic, to_owner, &doc.name),
+            };
+            doc.topic = Some(to_topic.to_string());
+            doc.owner = Some(to_owner.to_string());
+            self.put(&new_key, Some(&doc))?;
+            if !keep && new_key != key {
+                self.put(&key, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the docs of an owned topic and all its media
+    pub fn remove_topic(&self, owner: &str, topic: &str) -> anyhow::Result<()> {
+        let prefix = format!("media{SEP}{topic}{SEP}{owner}{SEP}");
+        for key in self.docs.scan_prefix(prefix.as_bytes()).keys() {
+            self.put(std::str::from_utf8(&key?)?, None)?;
+        }
+        self.put(&topic_key(topic, owner), None)
+    }
+
+    pub fn remove_media(&self, owner: &str, topic: &str, uid: &MediaUid) -> anyhow::Result<()> {
+        self.put(&media_key(topic, owner, uid), None)
+    }
+
+    /// All indexed media owned by a key
+    pub fn media_docs(&self, owner: &str) -> anyhow::Result<Vec<SearchDoc>> {
+        let mut acc = vec![];
+        for entry in self.docs.scan_prefix(format!("media{SEP}").as_bytes()) {
+            let (_, bytes) = entry?;
+            let doc: SearchDoc = serde_json::from_slice(&bytes)?;
+            if doc.owner.as_deref() == Some(owner) {
+                acc.push(doc);
+            }
+        }
+        Ok(acc)
+    }
+
+    /// Doc keys containing a term starting with the token
+    fn prefix_matches(&self, token: &str) -> anyhow::Result<BTreeSet<Vec<u8>>> {
+        let mut keys = BTreeSet::new();
+        for entry in self.terms.scan_prefix(token.as_bytes()) {
+            let (term_key, _) = entry?;
+            if let Some(i) = term_key.iter().position(|b| *b == SEP as u8) {
+                keys.insert(term_key[i + 1..].to_vec());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Every token in the query must prefix-match a term of the doc, a query
+    /// without tokens matches nothing. Only docs `visible` accepts are returned.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        visible: impl Fn(&SearchDoc) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<SearchResults> {
+        let mut keys: Option<BTreeSet<Vec<u8>>> = None;
+        for token in tokenize(&query.q) {
+            let matches = self.prefix_matches(&token)?;
+            keys = Some(match keys {
+                Some(acc) => acc.intersection(&matches).cloned().collect(),
+                None => matches,
+            });
+        }
+
+        let mut facets = FacetCounts::default();
+        let mut hits = vec![];
+        for key in keys.unwrap_or_default() {
+            let Some(doc) = self.get(&key)? else { continue };
+            if !doc.matches(query) || !visible(&doc)? {
+                continue;
+            }
+            facets.count(&doc);
+            hits.push(doc);
+        }
+        hits.sort_by_key(|d| d.kind);
+
+        let total = hits.len();
+        let hits = hits.into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT))
+            .collect();
+
+        Ok(SearchResults { total, hits, facets })
+    }
+}
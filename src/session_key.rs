@@ -1,18 +1,58 @@
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use actix_web::cookie::Key;
 
-const KEY_FILE: &str = "session_key.txt";
+const KEY_FILE: &str = "session_key";
+/// Where the key used to be written, relative to the working directory
+const LEGACY_KEY_FILE: &str = "session_key.txt";
 
-pub fn load_or_create_key() -> Key {
-    if Path::new(KEY_FILE).exists() {
+/// Default location of the key in the data dir
+pub fn default_path(root_dir: &Path) -> PathBuf {
+    root_dir.join(KEY_FILE)
+}
+
+/// Write a file only the server's user can read
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(bytes)
+}
+
+#[cfg(unix)]
+fn restrict(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+pub fn load_or_create_key(path: &Path) -> std::io::Result<Key> {
+    let legacy = Path::new(LEGACY_KEY_FILE);
+    if !path.exists() && legacy.exists() {
+        log::info!("Moving session key from {} to {}", legacy.display(), path.display());
+        write_private(path, &fs::read(legacy)?)?;
+        fs::remove_file(legacy)?;
+    }
+
+    if path.exists() {
         // Load the existing key
-        let bytes = fs::read(KEY_FILE).expect("Failed to read key file");
-        Key::from(&bytes)
+        restrict(path)?;
+        let bytes = fs::read(path)?;
+        Key::try_from(bytes.as_slice())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
     } else {
         // Create a new key
         let key = Key::generate();
-        fs::write(KEY_FILE, key.master()).expect("Failed to write key file");
-        key
+        write_private(path, key.master())?;
+        Ok(key)
     }
 }
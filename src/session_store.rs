@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use actix_session::storage::{
+    CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore, UpdateError,
+};
+use actix_web::cookie::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::types::{Args, SameSitePolicy};
+
+type SessionState = HashMap<String, String>;
+
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    state: SessionState,
+    /// Unix time after which the session is gone
+    expires: i64,
+}
+
+/// Session state kept in sled so the cookie only holds a random key and a
+/// deleted session is gone for good, keyed by that random key.
+#[derive(Clone)]
+pub struct SledSessionStore {
+    sessions: sled::Tree,
+}
+
+fn new_key() -> Result<SessionKey, anyhow::Error> {
+    SessionKey::try_from(hex::encode(rand::random::<[u8; 32]>()))
+        .map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+impl SledSessionStore {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            sessions: db.open_tree("session_state")?,
+        })
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<StoredSession>> {
+        let Some(bytes) = self.sessions.get(key)? else {
+            return Ok(None);
+        };
+        let stored: StoredSession = serde_json::from_slice(&bytes)?;
+        if stored.expires < chrono::Utc::now().timestamp() {
+            self.sessions.remove(key)?;
+            return Ok(None);
+        }
+        Ok(Some(stored))
+    }
+
+    fn put(&self, key: &str, state: SessionState, ttl: &Duration) -> anyhow::Result<()> {
+        let stored = StoredSession {
+            state,
+            expires: chrono::Utc::now().timestamp() + ttl.whole_seconds(),
+        };
+        self.sessions.insert(key, serde_json::to_vec(&stored)?)?;
+        Ok(())
+    }
+
+    /// Drop sessions past their expiry, returning how many there were
+    pub fn remove_expired(&self) -> anyhow::Result<usize> {
+        let now = chrono::Utc::now().timestamp();
+        let mut removed = 0;
+        for entry in self.sessions.iter() {
+            let (key, bytes) = entry?;
+            let stored: StoredSession = serde_json::from_slice(&bytes)?;
+            if stored.expires < now {
+                self.sessions.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl SessionStore for SledSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        self.get(session_key.as_ref())
+            .map(|stored| stored.map(|s| s.state))
+            .map_err(LoadError::Other)
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let key = new_key().map_err(SaveError::Other)?;
+        self.put(key.as_ref(), session_state, ttl).map_err(SaveError::Other)?;
+        Ok(key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        // A session that expired meanwhile starts over under a new key
+        let exists = self.get(session_key.as_ref()).map_err(UpdateError::Other)?.is_some();
+        let key = match exists {
+            true => session_key,
+            false => new_key().map_err(UpdateError::Other)?,
+        };
+        self.put(key.as_ref(), session_state, ttl).map_err(UpdateError::Other)?;
+        Ok(key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        if let Some(stored) = self.get(session_key.as_ref())? {
+            self.put(session_key.as_ref(), stored.state, ttl)?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        self.sessions.remove(session_key.as_ref())?;
+        Ok(())
+    }
+}
+
+/// Where session state lives, picked at startup
+#[derive(Clone)]
+pub enum SessionBackend {
+    /// Signed and encrypted in the cookie itself
+    Cookie,
+    Sled(SledSessionStore),
+}
+
+impl SessionStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            Self::Cookie => CookieSessionStore::default().load(session_key).await,
+            Self::Sled(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        match self {
+            Self::Cookie => CookieSessionStore::default().save(session_state, ttl).await,
+            Self::Sled(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            Self::Cookie => CookieSessionStore::default().update(session_key, session_state, ttl).await,
+            Self::Sled(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        match self {
+            Self::Cookie => CookieSessionStore::default().update_ttl(session_key, ttl).await,
+            Self::Sled(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        match self {
+            Self::Cookie => CookieSessionStore::default().delete(session_key).await,
+            Self::Sled(store) => store.delete(session_key).await,
+        }
+    }
+}
+
+/// Refuse session settings that would leak the session outside of dev mode
+pub fn check_settings(args: &Args) -> Result<(), String> {
+    if args.session.insecure_cookie && !args.dev {
+        return Err("session.insecure_cookie sends the session over plain http, it needs dev".to_string());
+    }
+    if args.session.lifetime_days <= 0 {
+        return Err("session.lifetime_days must be at least 1".to_string());
+    }
+    // Browsers drop SameSite=None cookies that aren't marked secure
+    if args.session.same_site == SameSitePolicy::None && args.session.insecure_cookie {
+        return Err("session.same_site none needs a secure cookie, drop session.insecure_cookie".to_string());
+    }
+    Ok(())
+}
+
+/// Warning for a UI origin the session cookie may never reach. Fetches from
+/// another site only carry SameSite=None cookies, but a UI on a subdomain of
+/// the same site is fine with lax or strict.
+pub fn same_site_warning(args: &Args) -> Option<String> {
+    let ui_origin = args.cors.ui_origin.as_ref()?;
+    (args.session.same_site != SameSitePolicy::None).then(|| format!(
+        "cors.ui_origin {} only gets the session cookie if it is on the same site, \
+         set session.same_site to none otherwise", ui_origin))
+}
@@ -0,0 +1,593 @@
+use std::collections::{BTreeMap, HashSet};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use sled::Transactional;
+use crate::db::{abort, tx_err};
+use crate::types::topic::{Index, IndexPolicy, MediaUid, OwnedTopicId};
+
+const SEP: char = '\0';
+/// Separates a child tag from its parent, as in "trips/alaska"
+pub const TAG_SEP: char = '/';
+/// Longest chain of aliases followed when resolving a tag
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// Tag indexes kept in sled. `indexes` maps a tag name to its json `Index`
+/// listing the ids of its owned topics, and `topic_tags` is the reverse map
+/// with one `{topic id}\0{tag}` key per tagged topic. Both trees are always
+/// updated in the same transaction.
+/// `aliases` maps old names of renamed or merged tags to their new name.
+/// Single media are tagged within the topic they are in through
+/// `media_tags` keyed by `{tag}\0{topic id}\0{media}` and its reverse map
+/// `tags_by_media` keyed by `{topic id}\0{media}\0{tag}`.
+#[derive(Clone)]
+pub struct TagDb {
+    indexes: sled::Tree,
+    topic_tags: sled::Tree,
+    aliases: sled::Tree,
+    media_tags: sled::Tree,
+    tags_by_media: sled::Tree,
+}
+
+/// Lowercase and clean up each level of a tag path so that "Alaska",
+/// "alaska" and " trips / Alaska " end up as "alaska" and "trips/alaska"
+pub fn normalize_tag(tag: &str) -> String {
+    tag.replace("%2F", "/")
+        .replace("%2f", "/")
+        .split(TAG_SEP)
+        .map(|part| part.trim()
+            .to_lowercase()
+            .replace("%20", "-")
+            .replace(' ', "-")
+            .replace('.', "_"))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The tag along with all its parents, "a/b/c" gives "a", "a/b" and "a/b/c"
+pub fn with_ancestors<'a>(tags: impl IntoIterator<Item = &'a String>) -> HashSet<String> {
+    let mut acc = HashSet::new();
+    for tag in tags {
+        let mut end = 0;
+        for part in tag.split(TAG_SEP) {
+            end += part.len();
+            acc.insert(tag[..end].to_string());
+            end += TAG_SEP.len_utf8();
+        }
+    }
+    acc
+}
+
+fn topic_tag_key(topic_id: &str, tag: &str) -> String {
+    format!("{topic_id}{SEP}{tag}")
+}
+
+/// Indexes used to list bare topic names shared by every owner of the name
+fn is_topic_id(topic: &str) -> bool {
+    serde_json::from_str::<OwnedTopicId>(topic).is_ok()
+}
+
+fn media_tag_key(tag: &str, topic_id: &str, media: &MediaUid) -> String {
+    format!("{tag}{SEP}{topic_id}{SEP}{media}")
+}
+
+fn tag_by_media_key(topic_id: &str, media: &MediaUid, tag: &str) -> String {
+    format!("{topic_id}{SEP}{media}{SEP}{tag}")
+}
+
+type TxResult<T> = Result<T, ConflictableTransactionError<anyhow::Error>>;
+
+fn tx_get(indexes: &TransactionalTree, tag: &str) -> TxResult<Option<Index>> {
+    indexes.get(tag.as_bytes())?
+        .map(|bytes| serde_json::from_slice(&bytes))
+        .transpose()
+        .map_err(|e| abort(e.into()))
+}
+
+fn tx_put(indexes: &TransactionalTree, index: &Index) -> TxResult<()> {
+    let bytes = serde_json::to_vec(index)
+        .map_err(|e| abort(e.into()))?;
+    indexes.insert(index.name.as_bytes(), bytes)?;
+    Ok(())
+}
+
+/// Move the media tagged with `from` to `to`, or untag them if `to` is None
+fn tx_move_media(
+    media_tags: &TransactionalTree,
+    tags_by_media: &TransactionalTree,
+    tagged: &[(String, MediaUid)],
+    from: &str,
+    to: Option<&str>,
+) -> TxResult<()> {
+    for (topic_id, media) in tagged {
+        media_tags.remove(media_tag_key(from, topic_id, media).as_bytes())?;
+        tags_by_media.remove(tag_by_media_key(topic_id, media, from).as_bytes())?;
+        if let Some(to) = to {
+            media_tags.insert(media_tag_key(to, topic_id, media).as_bytes(), &[])?;
+            tags_by_media.insert(tag_by_media_key(topic_id, media, to).as_bytes(), &[])?;
+        }
+    }
+    Ok(())
+}
+
+impl TagDb {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            indexes: db.open_tree("indexes")?,
+            topic_tags: db.open_tree("topic_tags")?,
+            aliases: db.open_tree("tag_aliases")?,
+            media_tags: db.open_tree("media_tags")?,
+            tags_by_media: db.open_tree("tags_by_media")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    pub fn get(&self, tag: &str) -> anyhow::Result<Option<Index>> {
+        self.indexes.get(tag)?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    /// Follow aliases from old tag names, an existing index always wins over an alias
+    pub fn resolve(&self, tag: &str) -> anyhow::Result<String> {
+        let mut tag = tag.to_string();
+        for _ in 0..MAX_ALIAS_DEPTH {
+            if self.indexes.contains_key(&tag)? {
+                break;
+            }
+            match self.aliases.get(&tag)? {
+                Some(target) => tag = String::from_utf8(target.to_vec())?,
+                None => break,
+            }
+        }
+        Ok(tag)
+    }
+
+    /// Names of all tags below the tag in the hierarchy
+    pub fn descendants(&self, tag: &str) -> anyhow::Result<Vec<String>> {
+        let prefix = format!("{tag}{TAG_SEP}");
+        self.indexes.scan_prefix(prefix.as_bytes())
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    /// The index with the topics of all its descendants merged in. A parent that
+    /// only exists through its children is returned without an owner.
+    pub fn get_tree(&self, tag: &str) -> anyhow::Result<Option<Index>> {
+        let descendants = self.descendants(tag)?;
+        let mut index = match self.get(tag)? {
+            Some(index) => index,
+            None if descendants.is_empty() => return Ok(None),
+            None => Index::new(tag.to_string(), None),
+        };
+        for child in descendants {
+            if let Some(child) = self.get(&child)? {
+                index.topics.extend(child.topics);
+            }
+        }
+        Ok(Some(index))
+    }
+
+    pub fn all(&self) -> anyhow::Result<Vec<Index>> {
+        self.indexes.iter()
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+
+    pub fn tags_for_topic(&self, topic_id: &str) -> anyhow::Result<HashSet<String>> {
+        let prefix = format!("{topic_id}{SEP}");
+        let mut tags = HashSet::new();
+        for key in self.topic_tags.scan_prefix(prefix.as_bytes()).keys() {
+            let key = key?;
+            tags.insert(String::from_utf8(key[prefix.len()..].to_vec())?);
+        }
+        Ok(tags)
+    }
+
+    /// Tag the topic, creating the index owned by the caller if it doesn't exist
+    pub fn add_tag(&self, topic_id: &str, tag: &str, caller: &str) -> anyhow::Result<Index> {
+        (&self.indexes, &self.topic_tags).transaction(|(indexes, topic_tags)| {
+            let mut index = tx_get(indexes, tag)?
+                .unwrap_or_else(|| Index::new(tag.to_string(), Some(caller.to_string())));
+            index.topics.insert(topic_id.to_string());
+            tx_put(indexes, &index)?;
+            topic_tags.insert(topic_tag_key(topic_id, tag).as_bytes(), &[])?;
+            Ok(index)
+        }).map_err(tx_err)
+    }
+
+    /// Untag the topic, the index is kept even when left without topics
+    pub fn rm_tag(&self, topic_id: &str, tag: &str) -> anyhow::Result<Index> {
+        (&self.indexes, &self.topic_tags).transaction(|(indexes, topic_tags)| {
+            let mut index = tx_get(indexes, tag)?
+                .ok_or_else(|| abort(anyhow::anyhow!("Tag does not exist")))?;
+            index.topics.remove(topic_id);
+            topic_tags.remove(topic_tag_key(topic_id, tag).as_bytes())?;
+            tx_put(indexes, &index)?;
+            Ok(index)
+        }).map_err(tx_err)
+    }
+
+    /// Create an index with the given topic ids, fails if it already exists
+    pub fn create(
+        &self,
+        tag: &str,
+        topics: HashSet<String>,
+        owner: &str,
+    ) -> anyhow::Result<Index> {
+        (&self.indexes, &self.topic_tags).transaction(|(indexes, topic_tags)| {
+            if tx_get(indexes, tag)?.is_some() {
+                return Err(abort(anyhow::anyhow!("Tag {} already exists", tag)));
+            }
+            let mut index = Index::new(tag.to_string(), Some(owner.to_string()));
+            index.topics = topics.clone();
+            for topic in index.topics.iter() {
+                topic_tags.insert(topic_tag_key(topic, tag).as_bytes(), &[])?;
+            }
+            tx_put(indexes, &index)?;
+            Ok(index)
+        }).map_err(tx_err)
+    }
+
+    /// Move an index and its descendants to a new name, leaving aliases behind so
+    /// old links keep working. Fails if any of the new names are taken.
+    /// Returns the old name and new index of everything that moved.
+    pub fn rename(&self, tag: &str, new_tag: &str) -> anyhow::Result<Vec<(String, Index)>> {
+        if new_tag == tag || new_tag.starts_with(&format!("{tag}{TAG_SEP}")) {
+            return Err(anyhow::anyhow!("Can't move tag {} below itself", tag));
+        }
+        let mut names = vec![tag.to_string()];
+        names.extend(self.descendants(tag)?);
+        let tagged = names.iter()
+            .map(|name| self.tagged_media(name))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let trees = (&self.indexes, &self.topic_tags, &self.aliases, &self.media_tags, &self.tags_by_media);
+        trees.transaction(|(indexes, topic_tags, aliases, media_tags, tags_by_media)| {
+            let mut moved = vec![];
+            for (name, tagged) in names.iter().zip(tagged.iter()) {
+                let new_name = format!("{new_tag}{}", &name[tag.len()..]);
+                let mut index = tx_get(indexes, name)?
+                    .ok_or_else(|| abort(anyhow::anyhow!("Tag {} does not exist", name)))?;
+                if tx_get(indexes, &new_name)?.is_some() {
+                    return Err(abort(anyhow::anyhow!("Tag {} already exists", new_name)));
+                }
+
+                for topic in index.topics.iter() {
+                    topic_tags.remove(topic_tag_key(topic, name).as_bytes())?;
+                    topic_tags.insert(topic_tag_key(topic, &new_name).as_bytes(), &[])?;
+                }
+                tx_move_media(media_tags, tags_by_media, tagged, name, Some(&new_name))?;
+                indexes.remove(name.as_bytes())?;
+                aliases.insert(name.as_bytes(), new_name.as_bytes())?;
+                aliases.remove(new_name.as_bytes())?;
+                index.name = new_name;
+                tx_put(indexes, &index)?;
+                moved.push((name.clone(), index));
+            }
+            Ok(moved)
+        }).map_err(tx_err)
+    }
+
+    /// Move all topics of a tag into another and leave an alias to the target.
+    /// Returns the merged target index.
+    pub fn merge(&self, tag: &str, target: &str) -> anyhow::Result<Index> {
+        if !self.descendants(tag)?.is_empty() {
+            return Err(anyhow::anyhow!("Merge or move the child tags of {} first", tag));
+        }
+        if tag == target {
+            return Err(anyhow::anyhow!("Can't merge a tag into itself"));
+        }
+        let tagged = self.tagged_media(tag)?;
+
+        let trees = (&self.indexes, &self.topic_tags, &self.aliases, &self.media_tags, &self.tags_by_media);
+        trees.transaction(|(indexes, topic_tags, aliases, media_tags, tags_by_media)| {
+            let source = tx_get(indexes, tag)?
+                .ok_or_else(|| abort(anyhow::anyhow!("Tag {} does not exist", tag)))?;
+            let mut merged = tx_get(indexes, target)?
+                .ok_or_else(|| abort(anyhow::anyhow!("Tag {} does not exist", target)))?;
+
+            for topic in source.topics.iter() {
+                topic_tags.remove(topic_tag_key(topic, tag).as_bytes())?;
+                topic_tags.insert(topic_tag_key(topic, target).as_bytes(), &[])?;
+            }
+            merged.topics.extend(source.topics);
+            tx_move_media(media_tags, tags_by_media, &tagged, tag, Some(target))?;
+            indexes.remove(tag.as_bytes())?;
+            aliases.insert(tag.as_bytes(), target.as_bytes())?;
+            tx_put(indexes, &merged)?;
+            Ok(merged)
+        }).map_err(tx_err)
+    }
+
+    /// Remove an index and untag all its topics and media, returning what was removed
+    pub fn delete(&self, tag: &str) -> anyhow::Result<Index> {
+        let tagged = self.tagged_media(tag)?;
+
+        let trees = (&self.indexes, &self.topic_tags, &self.media_tags, &self.tags_by_media);
+        trees.transaction(|(indexes, topic_tags, media_tags, tags_by_media)| {
+            let index = tx_get(indexes, tag)?
+                .ok_or_else(|| abort(anyhow::anyhow!("Tag does not exist")))?;
+            for topic in index.topics.iter() {
+                topic_tags.remove(topic_tag_key(topic, tag).as_bytes())?;
+            }
+            tx_move_media(media_tags, tags_by_media, &tagged, tag, None)?;
+            indexes.remove(tag.as_bytes())?;
+            Ok(index)
+        }).map_err(tx_err)
+    }
+
+    /// Tag a single media within a topic, creating the index owned by the
+    /// caller if it doesn't exist
+    pub fn add_media_tag(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        tag: &str,
+        caller: &str,
+    ) -> anyhow::Result<Index> {
+        let trees = (&self.indexes, &self.media_tags, &self.tags_by_media);
+        trees.transaction(|(indexes, media_tags, tags_by_media)| {
+            let index = match tx_get(indexes, tag)? {
+                Some(index) => index,
+                None => {
+                    let index = Index::new(tag.to_string(), Some(caller.to_string()));
+                    tx_put(indexes, &index)?;
+                    index
+                }
+            };
+            media_tags.insert(media_tag_key(tag, topic_id, media).as_bytes(), &[])?;
+            tags_by_media.insert(tag_by_media_key(topic_id, media, tag).as_bytes(), &[])?;
+            Ok(index)
+        }).map_err(tx_err)
+    }
+
+    pub fn rm_media_tag(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+        tag: &str,
+    ) -> anyhow::Result<()> {
+        (&self.media_tags, &self.tags_by_media).transaction(|(media_tags, tags_by_media)| {
+            tx_move_media(media_tags, tags_by_media, &[(topic_id.to_string(), media.clone())], tag, None)
+        }).map_err(tx_err)
+    }
+
+    pub fn tags_for_media(
+        &self,
+        topic_id: &str,
+        media: &MediaUid,
+    ) -> anyhow::Result<HashSet<String>> {
+        let prefix = format!("{topic_id}{SEP}{media}{SEP}");
+        self.tags_by_media.scan_prefix(prefix.as_bytes())
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?[prefix.len()..].to_vec())?))
+            .collect()
+    }
+
+    /// Media level tags of every tagged media in a topic
+    pub fn media_tags_for_topic(
+        &self,
+        topic_id: &str,
+    ) -> anyhow::Result<BTreeMap<MediaUid, HashSet<String>>> {
+        let prefix = format!("{topic_id}{SEP}");
+        let mut acc: BTreeMap<MediaUid, HashSet<String>> = BTreeMap::new();
+        for key in self.tags_by_media.scan_prefix(prefix.as_bytes()).keys() {
+            let key = key?;
+            let rest = std::str::from_utf8(&key[prefix.len()..])?;
+            if let Some((media, tag)) = rest.split_once(SEP) {
+                acc.entry(media.to_string()).or_default().insert(tag.to_string());
+            }
+        }
+        Ok(acc)
+    }
+
+    /// Move the media tags of a topic to another topic, or copy them when `keep` is set
+    pub fn move_topic_media(&self, from_id: &str, to_id: &str, keep: bool) -> anyhow::Result<()> {
+        let tagged = self.media_tags_for_topic(from_id)?;
+        (&self.media_tags, &self.tags_by_media).transaction(|(media_tags, tags_by_media)| {
+            for (media, tags) in tagged.iter() {
+                for tag in tags {
+                    if !keep {
+                        media_tags.remove(media_tag_key(tag, from_id, media).as_bytes())?;
+                        tags_by_media.remove(tag_by_media_key(from_id, media, tag).as_bytes())?;
+                    }
+                    media_tags.insert(media_tag_key(tag, to_id, media).as_bytes(), &[])?;
+                    tags_by_media.insert(tag_by_media_key(to_id, media, tag).as_bytes(), &[])?;
+                }
+            }
+            Ok(())
+        }).map_err(tx_err)
+    }
+
+    /// Untag every media of a topic, or only the given one
+    pub fn purge_media_tags(&self, topic_id: &str, media: Option<&MediaUid>) -> anyhow::Result<()> {
+        let mut tagged = self.media_tags_for_topic(topic_id)?;
+        if let Some(media) = media {
+            tagged.retain(|uid, _| uid == media);
+        }
+        (&self.media_tags, &self.tags_by_media).transaction(|(media_tags, tags_by_media)| {
+            for (media, tags) in tagged.iter() {
+                for tag in tags {
+                    media_tags.remove(media_tag_key(tag, topic_id, media).as_bytes())?;
+                    tags_by_media.remove(tag_by_media_key(topic_id, media, tag).as_bytes())?;
+                }
+            }
+            Ok(())
+        }).map_err(tx_err)
+    }
+
+    /// Topic id and uid of every media tagged with exactly this tag
+    pub fn tagged_media(&self, tag: &str) -> anyhow::Result<Vec<(String, MediaUid)>> {
+        let prefix = format!("{tag}{SEP}");
+        let mut acc = vec![];
+        for key in self.media_tags.scan_prefix(prefix.as_bytes()).keys() {
+            let key = key?;
+            let rest = std::str::from_utf8(&key[prefix.len()..])?;
+            if let Some((topic_id, media)) = rest.split_once(SEP) {
+                acc.push((topic_id.to_string(), media.to_string()));
+            }
+        }
+        Ok(acc)
+    }
+
+    /// Change who can add topics to an index
+    pub fn set_policy(
+        &self,
+        tag: &str,
+        policy: IndexPolicy,
+        invited: HashSet<String>,
+    ) -> anyhow::Result<Index> {
+        let index = self.indexes.transaction(|indexes| {
+            let mut index = tx_get(indexes, tag)?
+                .ok_or_else(|| abort(anyhow::anyhow!("Tag does not exist")))?;
+            index.policy = policy;
+            index.invited = invited.clone();
+            tx_put(indexes, &index)?;
+            Ok(index)
+        }).map_err(tx_err)?;
+        Ok(index)
+    }
+
+    /// Merge an index, like one read from an old indexes/*.json file. Names
+    /// are normalized so differently cased copies of a tag end up together.
+    pub fn import(&self, index: &Index) -> anyhow::Result<()> {
+        let name = normalize_tag(&index.name);
+        (&self.indexes, &self.topic_tags).transaction(|(indexes, topic_tags)| {
+            let mut merged = tx_get(indexes, &name)?
+                .unwrap_or_else(|| Index::new(name.clone(), index.owner.clone()));
+            merged.topics.extend(index.topics.iter().cloned());
+            for topic in merged.topics.iter() {
+                topic_tags.insert(topic_tag_key(topic, &name).as_bytes(), &[])?;
+            }
+            tx_put(indexes, &merged)?;
+            Ok(())
+        }).map_err(tx_err)
+    }
+
+    /// Whether some index still lists bare topic names
+    pub fn has_bare_topics(&self) -> anyhow::Result<bool> {
+        Ok(self.all()?.iter().any(|index| index.topics.iter().any(|t| !is_topic_id(t))))
+    }
+
+    /// Replace the bare topic names of every index with the topic ids
+    /// `topic_ids` gives for them and rebuild `topic_tags` to match
+    pub fn rekey_topics(
+        &self,
+        topic_ids: impl Fn(&str) -> anyhow::Result<Vec<String>>,
+    ) -> anyhow::Result<()> {
+        let mut rekeyed = vec![];
+        for mut index in self.all()? {
+            let mut topics = HashSet::new();
+            for topic in index.topics.drain() {
+                if is_topic_id(&topic) {
+                    topics.insert(topic);
+                } else {
+                    topics.extend(topic_ids(&topic)?);
+                }
+            }
+            index.topics = topics;
+            rekeyed.push(index);
+        }
+
+        self.topic_tags.clear()?;
+        (&self.indexes, &self.topic_tags).transaction(|(indexes, topic_tags)| {
+            for index in rekeyed.iter() {
+                for topic_id in index.topics.iter() {
+                    topic_tags.insert(topic_tag_key(topic_id, &index.name).as_bytes(), &[])?;
+                }
+                tx_put(indexes, index)?;
+            }
+            Ok(())
+        }).map_err(tx_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> TagDb {
+        TagDb::open(&sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    fn topic_id(topic: &str, owner: &str) -> String {
+        OwnedTopicId::new(topic, owner).to_string().unwrap()
+    }
+
+    #[test]
+    fn rename_moves_children_topics_and_media() {
+        let tags = tags();
+        let trip = topic_id("trip", "k1");
+        tags.add_tag(&trip, "trips", "k1").unwrap();
+        tags.add_tag(&trip, "trips/alaska", "k1").unwrap();
+        tags.add_media_tag(&trip, &"m1".to_string(), "trips/alaska", "k1").unwrap();
+
+        let moved = tags.rename("trips", "travel").unwrap();
+        let names: Vec<(&str, &str)> = moved.iter()
+            .map(|(old, index)| (old.as_str(), index.name.as_str()))
+            .collect();
+        assert_eq!(names, vec![("trips", "travel"), ("trips/alaska", "travel/alaska")]);
+
+        assert!(tags.get("trips").unwrap().is_none());
+        assert!(tags.get("trips/alaska").unwrap().is_none());
+        assert!(tags.get("travel/alaska").unwrap().unwrap().topics.contains(&trip));
+        let expected: HashSet<String> = ["travel".to_string(), "travel/alaska".to_string()].into();
+        assert_eq!(tags.tags_for_topic(&trip).unwrap(), expected);
+        assert_eq!(tags.tagged_media("travel/alaska").unwrap(), vec![(trip.clone(), "m1".to_string())]);
+        assert!(tags.tagged_media("trips/alaska").unwrap().is_empty());
+        assert_eq!(tags.tags_for_media(&trip, &"m1".to_string()).unwrap(), ["travel/alaska".to_string()].into());
+    }
+
+    #[test]
+    fn rename_leaves_aliases() {
+        let tags = tags();
+        tags.add_tag(&topic_id("trip", "k1"), "trips/alaska", "k1").unwrap();
+        tags.rename("trips/alaska", "alaska").unwrap();
+        assert_eq!(tags.resolve("trips/alaska").unwrap(), "alaska");
+
+        // A new tag under the old name wins over the alias
+        tags.add_tag(&topic_id("trip", "k1"), "trips/alaska", "k1").unwrap();
+        assert_eq!(tags.resolve("trips/alaska").unwrap(), "trips/alaska");
+    }
+
+    #[test]
+    fn rename_refuses_taken_names_and_itself() {
+        let tags = tags();
+        let trip = topic_id("trip", "k1");
+        tags.add_tag(&trip, "a", "k1").unwrap();
+        tags.add_tag(&trip, "a/b", "k1").unwrap();
+        tags.add_tag(&trip, "c/b", "k1").unwrap();
+
+        assert!(tags.rename("a", "a").is_err());
+        assert!(tags.rename("a", "a/d").is_err());
+        assert!(tags.rename("a", "c/b").is_err());
+        // A taken child name fails the whole move
+        tags.add_tag(&trip, "c", "k1").unwrap();
+        assert!(tags.rename("c", "a").is_err());
+        assert!(tags.get("c/b").unwrap().is_some());
+        assert!(tags.get("c").unwrap().is_some());
+        assert!(tags.rename("missing", "x").is_err());
+    }
+
+    #[test]
+    fn rekey_topics_tags_each_owner() {
+        let tags = tags();
+        let mut index = Index::new("alaska".to_string(), Some("k1".to_string()));
+        index.topics = ["trip".to_string(), topic_id("cabin", "k3")].into();
+        tags.import(&index).unwrap();
+        assert!(tags.has_bare_topics().unwrap());
+
+        tags.rekey_topics(|topic| Ok(vec![topic_id(topic, "k1"), topic_id(topic, "k2")])).unwrap();
+        assert!(!tags.has_bare_topics().unwrap());
+        let expected: HashSet<String> = [topic_id("trip", "k1"), topic_id("trip", "k2"), topic_id("cabin", "k3")].into();
+        assert_eq!(tags.get("alaska").unwrap().unwrap().topics, expected);
+        assert!(tags.tags_for_topic("trip").unwrap().is_empty());
+        assert_eq!(tags.tags_for_topic(&topic_id("trip", "k2")).unwrap(), ["alaska".to_string()].into());
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use rustls::server::{ClientHello, ResolvesServerCert, ServerConfig};
+use rustls::sign::CertifiedKey;
+use crate::types::{Args, TlsArgs};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Read a PEM certificate chain and its private key
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Error reading certificate {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Error reading private key {}: {}", key_path.display(), e))?;
+    let key = provider().key_provider.load_private_key(key)
+        .map_err(|e| format!("Unsupported private key {}: {}", key_path.display(), e))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match()
+        .map_err(|e| format!("Key {} doesn't match certificate {}: {}", key_path.display(), cert_path.display(), e))?;
+    Ok(certified)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Serves the certificate last read from disk, so it can be replaced
+/// without a restart
+#[derive(Debug)]
+pub struct CertReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the certificate and key when they were read
+    loaded: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertReloader {
+    pub fn open(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, String> {
+        let loaded = (modified(&cert_path), modified(&key_path));
+        let current = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// Read the certificate again, keeping the old one if the new one is broken
+    pub fn reload(&self) {
+        let loaded = (modified(&self.cert_path), modified(&self.key_path));
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                log::info!("Reloaded TLS certificate {}", self.cert_path.display());
+            }
+            Err(e) => log::error!("Keeping the current TLS certificate: {}", e),
+        }
+        // Also on error, so a broken file is reported once and not on every check
+        *self.loaded.lock().unwrap() = loaded;
+    }
+
+    /// Whether the certificate or key changed on disk since they were read
+    fn changed(&self) -> bool {
+        *self.loaded.lock().unwrap() != (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// TLS config of the server, h2 and http/1.1 are offered by actix-web
+pub fn server_config(reloader: Arc<CertReloader>) -> Result<ServerConfig, String> {
+    Ok(ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Error setting up TLS: {}", e))?
+        .with_no_client_auth()
+        .with_cert_resolver(reloader))
+}
+
+/// Reload the certificate on SIGHUP and when its files change, which is
+/// checked every `reload_interval_secs`
+pub fn spawn_reloader(reloader: Arc<CertReloader>, args: &TlsArgs) {
+    #[cfg(unix)]
+    {
+        let reloader = reloader.clone();
+        actix_web::rt::spawn(async move {
+            use actix_web::rt::signal::unix::{signal, SignalKind};
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => return log::error!("Error listening for SIGHUP: {}", e),
+            };
+            while hangup.recv().await.is_some() {
+                log::info!("SIGHUP received, reloading TLS certificate");
+                reloader.reload();
+            }
+        });
+    }
+
+    if args.reload_interval_secs == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(args.reload_interval_secs);
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+            if reloader.changed() {
+                reloader.reload();
+            }
+        }
+    });
+}
+
+/// Both halves of the certificate are needed to serve TLS
+pub fn check_settings(args: &Args) -> Result<(), String> {
+    match (&args.tls.cert_path, &args.tls.key_path) {
+        (Some(_), None) => Err("tls.cert_path is set without tls.key_path".to_string()),
+        (None, Some(_)) => Err("tls.key_path is set without tls.cert_path".to_string()),
+        _ => Ok(()),
+    }
+}
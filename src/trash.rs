@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use crate::types::topic::{MediaUid, TopicData};
+
+const SEP: char = '\0';
+pub const DAY_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum TrashItem {
+    /// A whole topic with its history
+    Topic { data: TopicData },
+    /// A media removed from a topic
+    Media { topic: String, uid: MediaUid },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: u64,
+    pub owner: String,
+    /// Unix time of the deletion
+    pub deleted: i64,
+    pub item: TrashItem,
+}
+
+impl TrashEntry {
+    /// Media the entry keeps a reference to until it is purged
+    pub fn media(&self) -> Vec<MediaUid> {
+        match &self.item {
+            TrashItem::Topic { data } => data.list(),
+            TrashItem::Media { uid, .. } => vec![uid.clone()],
+        }
+    }
+}
+
+/// Soft deleted topics and media per owner, keyed by `{owner}\0{entry id}`
+/// with big endian ids so an owner's trash lists in deletion order.
+#[derive(Clone)]
+pub struct Trash {
+    db: sled::Db,
+    entries: sled::Tree,
+}
+
+fn entry_key(owner: &str, id: u64) -> Vec<u8> {
+    let mut key = format!("{owner}{SEP}").into_bytes();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+impl Trash {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            db: db.clone(),
+            entries: db.open_tree("trash")?,
+        })
+    }
+
+    pub fn put(&self, owner: &str, item: TrashItem) -> anyhow::Result<TrashEntry> {
+        let entry = TrashEntry {
+            id: self.db.generate_id()?,
+            owner: owner.to_string(),
+            deleted: chrono::Utc::now().timestamp(),
+            item,
+        };
+        self.entries.insert(entry_key(owner, entry.id), serde_json::to_vec(&entry)?)?;
+        Ok(entry)
+    }
+
+    pub fn get(&self, owner: &str, id: u64) -> anyhow::Result<Option<TrashEntry>> {
+        self.entries.get(entry_key(owner, id))?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    /// Remove an entry from the trash, returning whether it was there
+    pub fn take(&self, owner: &str, id: u64) -> anyhow::Result<bool> {
+        Ok(self.entries.remove(entry_key(owner, id))?.is_some())
+    }
+
+    pub fn list(&self, owner: &str) -> anyhow::Result<Vec<TrashEntry>> {
+        self.entries.scan_prefix(format!("{owner}{SEP}").as_bytes())
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+
+    /// Entries of every owner
+    pub fn all(&self) -> anyhow::Result<Vec<TrashEntry>> {
+        self.entries.iter()
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+
+    /// Entries of every owner deleted before the cutoff
+    pub fn expired(&self, cutoff: i64) -> anyhow::Result<Vec<TrashEntry>> {
+        let mut acc = vec![];
+        for bytes in self.entries.iter().values() {
+            let entry: TrashEntry = serde_json::from_slice(&bytes?)?;
+            if entry.deleted < cutoff {
+                acc.push(entry);
+            }
+        }
+        Ok(acc)
+    }
+}
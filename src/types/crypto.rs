@@ -1,3 +1,4 @@
+use base64::Engine;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -43,6 +44,11 @@ impl PublicKey {
     }
     */
 
+    pub fn from_base64(b64_str: &str) -> Option<Self> {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(b64_str).ok()?;
+        Some(PublicKey(bytes.try_into().ok()?))
+    }
+
     pub fn to_bytes(&self) -> [u8; 32] {
         self.0
     }
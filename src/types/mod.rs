@@ -3,10 +3,10 @@ pub mod topic;
 pub mod crypto;
 
 use std::path::PathBuf;
-use structopt::StructOpt;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
+use actix_web::http::StatusCode;
 //use acidjson::AcidJson;
 use anyhow::anyhow;
 use log::info;
@@ -15,60 +15,172 @@ pub use crypto::PublicKey;
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
 pub struct HashVal([u8; 32]);
 
-// TODO AnyError is now legacy, remove it
-#[derive(Debug)]
-pub struct AnyError {
-    err: anyhow::Error,
+/// Error of any request. Responds with the matching HTTP status and a JSON
+/// body `{"code": ..., "message": ...}` where the code is stable for clients.
+#[derive(Error, Debug)]
+pub enum ServerErr {
+    #[error("{0}")]
+    BadRequest(String),
+    /// No verified session, the client should authenticate again
+    #[error("{0}")]
+    Unauthenticated(String),
+    /// The session is verified for a different key than the one requested
+    #[error("{0}")]
+    KeyMismatch(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Error topic not found: `{0}`")]
+    TopicNotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    #[error("{0}")]
+    TooManyRequests(String),
+    /// The owner's storage quota is used up
+    #[error("{0}")]
+    QuotaExceeded(String),
+    /// The disk is too full to take more uploads
+    #[error("{0}")]
+    InsufficientStorage(String),
+    #[error("Filetype Error: `{0}`")]
+    FiletypeError(String),
+    #[error("Error: `{0}`")]
+    InvalidExtension(String),
+    #[error("Error with topic database")]
+    TopicDbError(#[from] sled::Error),
+    #[error("IO Error: `{0}`")]
+    IOError(#[from] std::io::Error),
+    #[error("JSON Error: `{0}`")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Error: `{0}`")]
+    CustomError(#[from] anyhow::Error),
 }
 
-impl actix_web::ResponseError for AnyError {
-    fn error_response(&self) -> actix_web::HttpResponse {
-        actix_web::HttpResponse::InternalServerError().body(self.err.to_string())
+impl ServerErr {
+    pub fn bad_request(msg: impl Into<String>) -> Self {
+        Self::BadRequest(msg.into())
+    }
+
+    pub fn unauthenticated(msg: impl Into<String>) -> Self {
+        Self::Unauthenticated(msg.into())
+    }
+
+    pub fn forbidden(msg: impl Into<String>) -> Self {
+        Self::Forbidden(msg.into())
     }
+
+    pub fn not_found(msg: impl Into<String>) -> Self {
+        Self::NotFound(msg.into())
+    }
+
+    pub fn conflict(msg: impl Into<String>) -> Self {
+        Self::Conflict(msg.into())
+    }
+
+    /// Machine readable code of the error, these don't change between versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthenticated(_) => "unauthenticated",
+            Self::KeyMismatch(_) => "key_mismatch",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::TopicNotFound(_) => "topic_not_found",
+            Self::Conflict(_) => "conflict",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::TooManyRequests(_) => "rate_limited",
+            Self::QuotaExceeded(_) => "quota_exceeded",
+            Self::InsufficientStorage(_) => "insufficient_storage",
+            Self::FiletypeError(_) | Self::InvalidExtension(_) => "unsupported_media_type",
+            Self::TopicDbError(_) | Self::IOError(_) | Self::JsonError(_) | Self::CustomError(_) => "internal",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
 }
 
-impl From<anyhow::Error> for AnyError {
-    fn from(err: anyhow::Error) -> Self {
-        Self { err }
+impl actix_web::ResponseError for ServerErr {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Self::KeyMismatch(_) | Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) | Self::TopicNotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PayloadTooLarge(_) | Self::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::FiletypeError(_) | Self::InvalidExtension(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::TopicDbError(_) | Self::IOError(_) | Self::JsonError(_) | Self::CustomError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        let status = self.status_code();
+        if status.is_server_error() {
+            log::error!("{:?}", self);
+        }
+        actix_web::HttpResponse::build(status).json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+        })
     }
 }
 
-impl::std::fmt::Display for AnyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.err)
+/// Errors raised by actix itself, such as a bad multipart stream, keep the
+/// status actix gave them
+impl From<actix_web::Error> for ServerErr {
+    fn from(e: actix_web::Error) -> Self {
+        let status = e.as_response_error().status_code();
+        let msg = e.to_string();
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest(msg),
+            StatusCode::UNAUTHORIZED => Self::Unauthenticated(msg),
+            StatusCode::FORBIDDEN => Self::Forbidden(msg),
+            StatusCode::NOT_FOUND => Self::NotFound(msg),
+            StatusCode::CONFLICT => Self::Conflict(msg),
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge(msg),
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequests(msg),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::FiletypeError(msg),
+            _ if status.is_client_error() => Self::BadRequest(msg),
+            _ => Self::CustomError(anyhow!(msg)),
+        }
     }
 }
 
-/*
-impl AnyError {
-    pub fn anyhow(s: &str) -> Self {
-        AnyError::from(anyhow::anyhow!(s))
+impl From<actix_session::SessionGetError> for ServerErr {
+    fn from(e: actix_session::SessionGetError) -> Self {
+        Self::CustomError(anyhow!("Session error: {}", e))
     }
 }
-*/
 
-#[derive(Error, Debug)]
-pub enum ServerErr {
-    #[error("Error with topic database")]
-    TopicDbError(#[from] sled::Error),
-    #[error("Error topic not found: `{0}`")]
-    TopicNotFound(String),
-    #[error("Filetype Error: `{0}`")]
-    FiletypeError(String),
-    #[error("IO Error: `{0}`")]
-    IOError(#[from] std::io::Error),
-    #[error("Error: `{0}`")]
-    InvalidExtension(String),
-    #[error("Error: `{0}`")]
-    CustomError(#[from] anyhow::Error),
+impl From<actix_session::SessionInsertError> for ServerErr {
+    fn from(e: actix_session::SessionInsertError) -> Self {
+        Self::CustomError(anyhow!("Session error: {}", e))
+    }
 }
 
-impl actix_web::ResponseError for ServerErr {
-    fn error_response(&self) -> actix_web::HttpResponse {
-        actix_web::HttpResponse::InternalServerError().body(self.to_string())
+impl From<actix_multipart::MultipartError> for ServerErr {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        Self::BadRequest(e.to_string())
     }
 }
 
+/// Reject malformed JSON bodies, paths and queries with our error body
+/// instead of actix's plain text
+pub fn extractor_error<E: std::fmt::Display>(e: E, _req: &actix_web::HttpRequest) -> actix_web::Error {
+    ServerErr::BadRequest(e.to_string()).into()
+}
+
 
 #[derive(Serialize, Deserialize)]
 pub struct VerificationPayload {
@@ -76,24 +188,340 @@ pub struct VerificationPayload {
     pub signature: Vec<u8>,
 }
 
+/// Claim a handle, signed for `users::claim_purpose` with a fresh challenge
+#[derive(Deserialize)]
+pub struct HandleClaimPayload {
+    pub public_key: PublicKey,
+    pub handle: String,
+    pub signature: Vec<u8>,
+}
+
+/// Rotate to a new key, signed by the old key for `keys::rotate_purpose`
+/// with a fresh challenge
+#[derive(Deserialize)]
+pub struct RotateKeyPayload {
+    pub old_key: PublicKey,
+    pub new_key: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct CaptionPayload {
+    pub media: topic::MediaUid,
+    /// Empty to clear the caption
+    pub caption: String,
+}
+
+/// Fields left out are unchanged, empty strings clear them
+#[derive(Deserialize)]
+pub struct TopicInfoPayload {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub cover: Option<topic::MediaUid>,
+}
+
+#[derive(Deserialize)]
+pub struct CommentPayload {
+    pub body: String,
+    /// Comment id this replies to
+    pub parent: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct EditCommentPayload {
+    pub body: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReactionPayload {
+    pub emoji: String,
+}
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub after: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// Link the caller's key to the account of `inviter`, which signs for
+/// `keys::link_purpose` with a challenge from the caller's session
+#[derive(Deserialize)]
+pub struct LinkDevicePayload {
+    pub inviter: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct ProfilePayload {
+    pub display_name: Option<String>,
+}
+
+/// Page through topic listings, `after` is the `next` of the previous page
+#[derive(Deserialize)]
+pub struct TopicPageQuery {
+    pub after: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Filters on a topic's image list by the requesting key's own marks
+#[derive(Deserialize)]
+pub struct ImageListQuery {
+    #[serde(default)]
+    pub favorites: bool,
+    pub min_rating: Option<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct NewIndexPayload {
+    pub name: String,
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct IndexPolicyPayload {
+    pub policy: topic::IndexPolicy,
+    #[serde(default)]
+    pub invited: HashSet<String>,
+}
+
 #[derive(Clone)]
 pub struct ServerState {
     pub args: Args,
     pub topic_db: sled::Tree,
+    pub search: crate::search::SearchIndex,
+    pub comments: crate::comments::Comments,
+    pub favorites: crate::favorites::Favorites,
+    pub albums: crate::albums::SmartAlbums,
+    pub tags: crate::tags::TagDb,
+    pub handles: crate::users::Handles,
+    pub keys: crate::keys::Keys,
+    pub directory: crate::directory::TopicDirectory,
+    pub refs: crate::refs::MediaRefs,
+    pub trash: crate::trash::Trash,
+    pub signatures: crate::auth::SeenSignatures,
+    pub auth_attempts: crate::auth::FailedAttempts,
     pub thumbnail_sender: smol::channel::Sender<PathBuf>,
 }
 
-#[derive(StructOpt, Debug, Clone)]
-#[structopt(name = "sangha")]
+/// Server settings, read from the TOML config file then overridden by
+/// `IMG_*` environment variables and command line flags, see `config`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Args {
-    #[structopt(short, long, default_value = ".")]
     pub root_dir: PathBuf,
-    #[structopt(short, long, default_value = "2342")]
-    pub port: u32,
-    #[structopt(short, long)]
-    pub migrate: bool,
-    #[structopt(short, long, default_value = "./topic_db")]
     pub db_path: PathBuf,
+    /// Addresses to listen on as `host:port`
+    pub bind: Vec<String>,
+    /// Rebuild the search index and other derived data at startup
+    #[serde(skip)]
+    pub migrate: bool,
+    /// Log filter used when RUST_LOG isn't set
+    pub log_level: String,
+    /// Days deleted topics and media stay in the trash before they are purged
+    pub trash_retention_days: i64,
+    /// Allow insecure settings for local development
+    pub dev: bool,
+    pub thumbnails: ThumbnailArgs,
+    pub auth: AuthArgs,
+    pub session: SessionArgs,
+    pub cors: CorsArgs,
+    pub tls: TlsArgs,
+    pub uploads: UploadArgs,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThumbnailArgs {
+    /// Longest side of a thumbnail in pixels
+    pub max_size: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthArgs {
+    /// Public origin of the server like https://img.example.com, signatures
+    /// are bound to it. Required outside of dev, where it defaults to the
+    /// first bind address.
+    pub origin: Option<String>,
+    /// Largest body a signed request can have, it is held in memory until
+    /// its hash is checked. Larger uploads need a session.
+    pub max_signed_body_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionArgs {
+    /// Key signing session cookies, defaults to session_key in the root dir
+    pub key_path: Option<PathBuf>,
+    /// Send the session cookie over plain http too, needs dev
+    pub insecure_cookie: bool,
+    pub same_site: SameSitePolicy,
+    /// Days a sign in lasts
+    pub lifetime_days: i64,
+    /// Keep session state in the database instead of the cookie
+    pub server_store: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsArgs {
+    /// Origin the web UI is served from, allowed cross origin with the session cookie
+    pub ui_origin: Option<String>,
+    /// Other origins allowed to make cross origin requests, * for any
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    /// Let the other origins send the session cookie too
+    pub credentials: bool,
+    /// Seconds browsers may cache a preflight response
+    pub max_age: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsArgs {
+    /// PEM certificate chain, serve https on every bind address when set
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub key_path: Option<PathBuf>,
+    /// Seconds between checks whether the certificate changed on disk, 0 to
+    /// only reload on SIGHUP
+    pub reload_interval_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadArgs {
+    /// Largest single file in bytes
+    pub max_file_bytes: u64,
+    /// Largest upload request in bytes, all of its files together
+    pub max_request_bytes: u64,
+    /// Bytes of media each key may own, 0 for no limit
+    pub quota_bytes: u64,
+    /// Quotas of single keys overriding `quota_bytes`
+    pub quotas: HashMap<String, u64>,
+    /// Free bytes to keep on the disk of the root dir, uploads are refused below it
+    pub min_free_bytes: u64,
+}
+
+impl UploadArgs {
+    /// Storage quota of a key, None if it has none
+    pub fn quota(&self, key: &str) -> Option<u64> {
+        let quota = self.quotas.get(key).copied().unwrap_or(self.quota_bytes);
+        (quota > 0).then_some(quota)
+    }
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            root_dir: PathBuf::from("."),
+            db_path: PathBuf::from("./topic_db"),
+            bind: vec!["localhost:2342".to_string()],
+            migrate: false,
+            log_level: "info".to_string(),
+            trash_retention_days: 30,
+            dev: false,
+            thumbnails: ThumbnailArgs::default(),
+            auth: AuthArgs::default(),
+            session: SessionArgs::default(),
+            cors: CorsArgs::default(),
+            tls: TlsArgs::default(),
+            uploads: UploadArgs::default(),
+        }
+    }
+}
+
+impl Default for ThumbnailArgs {
+    fn default() -> Self {
+        Self { max_size: 500 }
+    }
+}
+
+impl Default for SessionArgs {
+    fn default() -> Self {
+        Self {
+            key_path: None,
+            insecure_cookie: false,
+            same_site: SameSitePolicy::Lax,
+            lifetime_days: 30,
+            server_store: false,
+        }
+    }
+}
+
+impl Default for CorsArgs {
+    fn default() -> Self {
+        Self {
+            ui_origin: None,
+            origins: vec![],
+            methods: vec!["GET".to_string(), "POST".to_string()],
+            credentials: false,
+            max_age: 3600,
+        }
+    }
+}
+
+impl Default for TlsArgs {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            reload_interval_secs: 60,
+        }
+    }
+}
+
+impl Default for UploadArgs {
+    fn default() -> Self {
+        Self {
+            max_file_bytes: 100 * 1024 * 1024,
+            max_request_bytes: 500 * 1024 * 1024,
+            quota_bytes: 0,
+            quotas: HashMap::new(),
+            min_free_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for AuthArgs {
+    fn default() -> Self {
+        Self {
+            origin: None,
+            max_signed_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl std::str::FromStr for SameSitePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lax" => Ok(Self::Lax),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Unknown SameSite {}, use strict, lax or none", s)),
+        }
+    }
+}
+
+impl From<SameSitePolicy> for actix_web::cookie::SameSite {
+    fn from(policy: SameSitePolicy) -> Self {
+        match policy {
+            SameSitePolicy::Strict => Self::Strict,
+            SameSitePolicy::Lax => Self::Lax,
+            SameSitePolicy::None => Self::None,
+        }
+    }
 }
 
 /*
@@ -1,9 +1,20 @@
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use crate::PublicKey;
 
 pub type MediaUid = String;
 
+/// Topic names are slugs shared by every owner, an owned topic is addressed
+/// as `{owner}/{slug}`. Slugs never contain '.' so the sled key
+/// `{slug}.{owner}` splits back unambiguously on the first dot.
+pub fn normalize_topic(topic: &str) -> String {
+    topic.to_lowercase()
+        .replace(" ", "-")
+        .replace("%20", "-")
+        .replace(".", "_")
+        .trim().to_string()
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TopicData {
     /// Topic name
@@ -14,6 +25,12 @@ pub struct TopicData {
     /// A stack of revision operations
     pub revs: Vec<RevisionOp>,
     pub owner: Option<PublicKey>,
+    /// Unix time of the last change
+    #[serde(default)]
+    pub modified: Option<i64>,
+    /// Listed in the public directory and readable by anyone
+    #[serde(default)]
+    pub public: bool,
 }
 
 impl TopicData {
@@ -26,6 +43,8 @@ impl TopicData {
             name,
             revs: vec![],
             owner,
+            modified: None,
+            public: false,
         };
         t.add(uids);
         t
@@ -65,17 +84,183 @@ impl TopicData {
             match rev {
                 RevisionOp::Add(v) => acc.append(&mut v.clone()),
                 RevisionOp::Del(v) => acc.retain(|x| !v.contains(x)),
+                _ => {}
             }
         }
 
         acc
     }
+
+    /// An empty caption clears it
+    pub fn set_caption(&mut self, media: MediaUid, caption: String) {
+        self.revs.push(RevisionOp::Caption(media, caption));
+    }
+
+    pub fn set_title(&mut self, title: String) {
+        self.revs.push(RevisionOp::Title(title));
+    }
+
+    pub fn set_description(&mut self, description: String) {
+        self.revs.push(RevisionOp::Description(description));
+    }
+
+    pub fn set_cover(&mut self, media: Option<MediaUid>) {
+        self.revs.push(RevisionOp::Cover(media));
+    }
+
+    /// Latest caption of each media
+    pub fn captions(&self) -> HashMap<MediaUid, String> {
+        let mut acc = HashMap::new();
+
+        for rev in self.revs.iter() {
+            if let RevisionOp::Caption(media, caption) = rev {
+                if caption.is_empty() {
+                    acc.remove(media);
+                } else {
+                    acc.insert(media.clone(), caption.clone());
+                }
+            }
+        }
+
+        acc
+    }
+
+    pub fn info(&self) -> TopicInfo {
+        let mut info = TopicInfo::default();
+        let non_empty = |s: &String| (!s.is_empty()).then(|| s.clone());
+
+        for rev in self.revs.iter() {
+            match rev {
+                RevisionOp::Title(t) => info.title = non_empty(t),
+                RevisionOp::Description(d) => info.description = non_empty(d),
+                RevisionOp::Cover(c) => info.cover = c.clone(),
+                _ => {}
+            }
+        }
+
+        // A removed cover image falls back to none
+        if let Some(ref cover) = info.cover {
+            if !self.contains(cover) {
+                info.cover = None;
+            }
+        }
+
+        info
+    }
+
+    /// The media list along with captions and topic info
+    pub fn listing(&self) -> TopicListing {
+        let mut captions = self.captions();
+        let media = self.list().into_iter()
+            .map(|uid| MediaEntry {
+                caption: captions.remove(&uid),
+                uid,
+            })
+            .collect();
+
+        TopicListing {
+            info: self.info(),
+            media,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct TopicInfo {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub cover: Option<MediaUid>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MediaEntry {
+    pub uid: MediaUid,
+    pub caption: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct TopicListing {
+    #[serde(flatten)]
+    pub info: TopicInfo,
+    pub media: Vec<MediaEntry>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Index {
     pub name: String,
     pub topics: HashSet<String>,
+    /// Public key of the creator, indexes from before ownership have none
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub policy: IndexPolicy,
+    /// Keys allowed to add topics to an invite only index
+    #[serde(default)]
+    pub invited: HashSet<String>,
+}
+
+/// Who can add topics to an index
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexPolicy {
+    /// Anyone can tag their topics
+    #[default]
+    Open,
+    /// Only the owner and invited keys
+    InviteOnly,
+    /// Only the owner
+    Closed,
+}
+
+impl Index {
+    pub fn new(name: String, owner: Option<String>) -> Self {
+        Self {
+            name,
+            topics: HashSet::new(),
+            owner,
+            policy: IndexPolicy::default(),
+            invited: HashSet::new(),
+        }
+    }
+
+    pub fn is_owner(&self, pubkey: &str) -> bool {
+        self.owner.as_deref() == Some(pubkey)
+    }
+
+    pub fn can_add(&self, pubkey: &str) -> bool {
+        match self.policy {
+            IndexPolicy::Open => true,
+            IndexPolicy::InviteOnly => self.is_owner(pubkey) || self.invited.contains(pubkey),
+            IndexPolicy::Closed => self.is_owner(pubkey),
+        }
+    }
+}
+
+/// An index with a preview of media from its topics
+#[derive(Serialize)]
+pub struct IndexPreview {
+    #[serde(flatten)]
+    pub index: Index,
+    /// One cover media per topic the viewer can see
+    pub preview: Vec<MediaUid>,
+    /// Tags below this one, their topics are included in `topics`
+    pub children: Vec<String>,
+    /// Single media tagged with this tag or its children in topics the viewer can see
+    pub media: Vec<TaggedMedia>,
+}
+
+/// A media tagged on its own within a topic
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaggedMedia {
+    pub topic: String,
+    pub uid: MediaUid,
+}
+
+#[derive(Serialize)]
+pub struct IndexSummary {
+    pub name: String,
+    /// Number of topics with the tag
+    pub topics: usize,
 }
 
 /// Topic ID associated with first 32 bits of public key
@@ -85,9 +270,40 @@ pub struct OwnedTopicId {
 }
 
 impl OwnedTopicId {
+    pub fn new(topic: &str, owner_id: &str) -> Self {
+        Self {
+            topic: normalize_topic(topic),
+            owner_id: owner_id.to_string(),
+        }
+    }
+
     pub fn to_string(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(&self)
     }
+
+    /// Key prefix shared by every owner's copy of a topic
+    pub fn topic_prefix(topic: &str) -> Result<Vec<u8>, serde_json::Error> {
+        // Drop the closing quote of the JSON string
+        let mut prefix = serde_json::to_vec(&format!("{}.", normalize_topic(topic)))?;
+        prefix.pop();
+        Ok(prefix)
+    }
+}
+
+/// Canonical public address of an owned topic
+#[derive(Serialize, Debug)]
+pub struct TopicAddress {
+    pub owner: String,
+    pub topic: String,
+}
+
+impl From<OwnedTopicId> for TopicAddress {
+    fn from(id: OwnedTopicId) -> Self {
+        Self {
+            owner: id.owner_id,
+            topic: id.topic,
+        }
+    }
 }
 
 impl Serialize for OwnedTopicId {
@@ -106,11 +322,13 @@ impl<'de> Deserialize<'de> for OwnedTopicId {
     where
         D: Deserializer<'de>,
     {
-        // Parse topic and owner_id from string delimited by '.'
+        // The topic slug ends at the first '.', the owner id may contain any character
         let s = String::deserialize(deserializer)?;
-        let mut split = s.split('.');
-        let topic = split.next().ok_or(serde::de::Error::custom("Expected topic"))?;
-        let owner_id = split.next().ok_or(serde::de::Error::custom("Expected owner_id"))?;
+        let (topic, owner_id) = s.split_once('.')
+            .ok_or(serde::de::Error::custom("Expected topic.owner_id"))?;
+        if topic.is_empty() || owner_id.is_empty() {
+            return Err(serde::de::Error::custom("Expected topic.owner_id"));
+        }
 
         Ok(OwnedTopicId {
             topic: topic.to_string(),
@@ -123,4 +341,8 @@ impl<'de> Deserialize<'de> for OwnedTopicId {
 pub enum RevisionOp {
     Add(Vec<MediaUid>),
     Del(Vec<MediaUid>),
+    Caption(MediaUid, String),
+    Title(String),
+    Description(String),
+    Cover(Option<MediaUid>),
 }
@@ -0,0 +1,84 @@
+use sled::Transactional;
+use crate::db::{abort, to_string, tx_err};
+
+pub const MIN_HANDLE_LEN: usize = 3;
+pub const MAX_HANDLE_LEN: usize = 32;
+/// Marks an `{id}` in a route as a handle. Base64 keys and their prefixes
+/// never contain it, so a handle can't shadow someone's key prefix.
+pub const HANDLE_PREFIX: char = '@';
+
+/// Unique usernames bound to public keys. `handles` maps a handle to its key
+/// and `handle_keys` is the reverse map, a key holds at most one handle.
+#[derive(Clone)]
+pub struct Handles {
+    handles: sled::Tree,
+    handle_keys: sled::Tree,
+}
+
+/// Lowercase the handle, without its `@`, and check it only uses `a-z`,
+/// `0-9`, `-` and `_`
+pub fn normalize_handle(handle: &str) -> anyhow::Result<String> {
+    let handle = handle.trim();
+    let handle = handle.strip_prefix(HANDLE_PREFIX).unwrap_or(handle).to_lowercase();
+    if handle.len() < MIN_HANDLE_LEN || handle.len() > MAX_HANDLE_LEN {
+        return Err(anyhow::anyhow!(
+            "Handle must be between {} and {} characters", MIN_HANDLE_LEN, MAX_HANDLE_LEN));
+    }
+    if !handle.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+        return Err(anyhow::anyhow!("Handle can only contain letters, digits, '-' and '_'"));
+    }
+    Ok(handle)
+}
+
+/// Purpose of a challenge signed by a key to claim a handle
+pub fn claim_purpose(handle: &str) -> String {
+    format!("claim-handle:{handle}")
+}
+
+impl Handles {
+    pub fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            handles: db.open_tree("handles")?,
+            handle_keys: db.open_tree("handle_keys")?,
+        })
+    }
+
+    /// Public key holding the handle
+    pub fn key_of(&self, handle: &str) -> anyhow::Result<Option<String>> {
+        self.handles.get(handle.as_bytes())?
+            .map(to_string)
+            .transpose()
+    }
+
+    pub fn handle_of(&self, pubkey: &str) -> anyhow::Result<Option<String>> {
+        self.handle_keys.get(pubkey.as_bytes())?
+            .map(to_string)
+            .transpose()
+    }
+
+    /// Map an `@handle` to its key, None if nobody holds it. Anything else is
+    /// taken to be a key or key prefix already.
+    pub fn resolve(&self, id: &str) -> anyhow::Result<Option<String>> {
+        match id.strip_prefix(HANDLE_PREFIX) {
+            Some(handle) => self.key_of(&handle.to_lowercase()),
+            None => Ok(Some(id.to_string())),
+        }
+    }
+
+    /// Bind the handle to the key, releasing the key's previous handle
+    pub fn claim(&self, handle: &str, pubkey: &str) -> anyhow::Result<()> {
+        (&self.handles, &self.handle_keys).transaction(|(handles, handle_keys)| {
+            if let Some(owner) = handles.get(handle.as_bytes())? {
+                if owner != pubkey.as_bytes() {
+                    return Err(abort(anyhow::anyhow!("Handle {} is taken", handle)));
+                }
+                return Ok(());
+            }
+            if let Some(old) = handle_keys.insert(pubkey.as_bytes(), handle.as_bytes())? {
+                handles.remove(old)?;
+            }
+            handles.insert(handle.as_bytes(), pubkey.as_bytes())?;
+            Ok(())
+        }).map_err(tx_err)
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use smol::stream::StreamExt;
 use blake3::Hasher;
 use rand::Rng;
@@ -7,10 +7,11 @@ use crate::types::{
     crypto::PublicKey,
     topic::{
         TopicData,
-        Index
+        Index,
+        MediaUid,
+        OwnedTopicId,
     },
 };
-use std::collections::HashSet;
 use smol::io::{BufWriter, AsyncRead, AsyncWriteExt, AsyncReadExt, BufReader};
 use mime::Mime;
 use anyhow::anyhow;
@@ -19,6 +20,7 @@ use crate::types::{
     mimes::from_ext,
     ServerErr,
 };
+use crate::search::MediaMetadata;
 
 /// Get all topic file paths in the root directory
 pub async fn get_topic_ids(root_dir: &PathBuf) -> Result<Vec<PathBuf>> {
@@ -35,6 +37,93 @@ pub async fn get_topic_ids(root_dir: &PathBuf) -> Result<Vec<PathBuf>> {
     Ok(json_files)
 }
 
+/// Read a topic from the topic db by its OwnedTopicId string
+pub fn read_topic(
+    topic_db: &sled::Tree,
+    topic_id: &str,
+) -> Result<Option<TopicData>, ServerErr> {
+    match topic_db.get(topic_id)? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(bytes.as_ref())
+            .map_err(|e| anyhow!("Corrupt topic data for {}: {}", topic_id, e))?)),
+        None => Ok(None),
+    }
+}
+
+/// Public keys of every owner with a topic of this name
+pub fn topic_owners(
+    topic_db: &sled::Tree,
+    topic: &str,
+) -> Result<Vec<String>, ServerErr> {
+    let prefix = OwnedTopicId::topic_prefix(topic).map_err(|e| anyhow!(e))?;
+    let mut owners = vec![];
+    for key in topic_db.scan_prefix(prefix).keys() {
+        let id: OwnedTopicId = serde_json::from_slice(&key?)
+            .map_err(|e| anyhow!("Corrupt topic id: {}", e))?;
+        owners.push(id.owner_id);
+    }
+    Ok(owners)
+}
+
+/// Move every entry under one key prefix to another, or copy them when `keep` is set
+pub fn move_prefix(
+    tree: &sled::Tree,
+    from: &[u8],
+    to: &[u8],
+    keep: bool,
+) -> sled::Result<()> {
+    let mut batch = sled::Batch::default();
+    for entry in tree.scan_prefix(from) {
+        let (key, value) = entry?;
+        let mut new_key = to.to_vec();
+        new_key.extend_from_slice(&key[from.len()..]);
+        if !keep {
+            batch.remove(key);
+        }
+        batch.insert(new_key, value);
+    }
+    tree.apply_batch(batch)
+}
+
+pub fn remove_prefix(tree: &sled::Tree, prefix: &[u8]) -> sled::Result<()> {
+    let mut batch = sled::Batch::default();
+    for key in tree.scan_prefix(prefix).keys() {
+        batch.remove(key?);
+    }
+    tree.apply_batch(batch)
+}
+
+/// Delete original media files along with their thumbnails
+pub async fn delete_originals(root_dir: PathBuf, uids: Vec<MediaUid>) {
+    for uid in uids {
+        for path in [root_dir.join(&uid), root_dir.join("thumbnails").join(&uid)] {
+            match smol::fs::remove_file(&path).await {
+                Ok(()) => log::info!("Deleted {:?}", path),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Error deleting {:?}: {}", path, e),
+            }
+        }
+    }
+}
+
+/// The cover of a topic, otherwise its first image since videos have no thumbnail
+pub fn preview_media(td: &TopicData) -> Option<MediaUid> {
+    td.info().cover.or_else(|| td.list().into_iter().find(|uid| {
+        ext(&PathBuf::from(uid))
+            .and_then(from_ext)
+            .is_some_and(|mime| mime.type_() == mime::IMAGE)
+    }))
+}
+
+pub fn write_topic(
+    topic_db: &sled::Tree,
+    topic_id: &str,
+    td: &TopicData,
+) -> Result<(), ServerErr> {
+    let bytes = serde_json::to_vec(td).map_err(|e| anyhow!(e))?;
+    topic_db.insert(topic_id, bytes)?;
+    Ok(())
+}
+
 /// Convert topic paths into TopicData structs
 pub async fn serialize_topics(topics: &Vec<PathBuf>) -> Result<Vec<TopicData>> {
     let mut topic_data = vec![];
@@ -103,115 +192,93 @@ pub async fn save_thumbnail(
     .map_err(|e| anyhow::anyhow!("Error saving thumbnail for [{media_file1:?}]: {:?}", e))
 }
 
-/// List all index files in the root/indexes directory
+/// Read the EXIF fields used by search. Media without readable metadata (like
+/// most videos) still get a media type.
+pub async fn read_media_metadata(
+    media_file: PathBuf,
+    mime: &Mime,
+) -> MediaMetadata {
+    let media_type = Some(mime.type_().to_string());
+    let meta = smol::unblock(move || {
+        let metadata = rexiv2::Metadata::new_from_path(&media_file).ok()?;
+        let tag = |name: &str| metadata.get_tag_string(name).ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+
+        let camera = match (tag("Exif.Image.Make"), tag("Exif.Image.Model")) {
+            (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => model.or(make),
+        };
+        // Exif dates are formatted as "YYYY:MM:DD HH:MM:SS"
+        let year = tag("Exif.Photo.DateTimeOriginal")
+            .or_else(|| tag("Exif.Image.DateTime"))
+            .and_then(|date| date.get(..4).and_then(|y| y.parse().ok()));
+        let fields = [
+            "Exif.Photo.LensModel",
+            "Exif.Image.Artist",
+            "Exif.Image.ImageDescription",
+            "Exif.Image.Software",
+        ].iter().filter_map(|name| tag(name)).collect();
+
+        Some(MediaMetadata { camera, year, fields, media_type: None })
+    }).await.unwrap_or_default();
+
+    MediaMetadata { media_type, ..meta }
+}
+
+/// List all index files in the root/indexes directory, including those of
+/// child tags in sub directories
 pub async fn get_index_paths(root_dir: &PathBuf) -> Result<Vec<PathBuf>> {
     let index_dir = root_dir.join("indexes");
     if !index_dir.exists() {
         smol::fs::create_dir(&index_dir).await?;
     }
-    let mut entries = smol::fs::read_dir(index_dir).await?;
+    let mut dirs = vec![index_dir];
     let mut index_files = vec![];
-    while let Some(entry) = entries.try_next().await? {
-        let path = entry.path();
-        if path.extension().map(|ext| ext == "json").unwrap_or(false) {
-            index_files.push(path);
+    while let Some(dir) = dirs.pop() {
+        let mut entries = smol::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.try_next().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                dirs.push(path);
+            } else if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+                index_files.push(path);
+            }
         }
     }
 
     Ok(index_files)
 }
 
-/// Search all /indexes/*.json files for the existence of the topic
-pub async fn get_tags_for_topic(
-    root_dir: &PathBuf,
-    topic: &String,
-) -> Result<HashSet<String>> {
-    let index_paths = get_index_paths(root_dir).await?;
-    let mut tags = HashSet::new();
-    for index_path in index_paths {
-        let mut file = File::open(&index_path).await?;
-        let mut raw_json = vec![];
-        file.read_to_end(&mut raw_json).await?;
-
-        let index: Index = serde_json::from_slice(&raw_json)?;
-        if index.topics.contains(topic) {
-            tags.insert(index.name);
-        }
-    }
-
-    Ok(tags)
-}
-
-pub async fn add_tag_for_topic(
-    root_dir: &PathBuf,
-    topic: String,
-    tag: String,
+/// Mirror a tag index to root/indexes/{tag}.json so all data stays browsable in
+/// the root directory. Sled is the source of truth, None removes the file.
+pub async fn export_index(
+    root_dir: &Path,
+    tag: &str,
+    index: Option<&Index>,
 ) -> Result<()> {
-    let index_paths = get_index_paths(root_dir).await?;
-
-    // If root_dir/indexes directory doesnt exist, create it
-    let mut tag_path = root_dir.join("indexes");
-    if !tag_path.exists() {
-        smol::fs::create_dir(&tag_path).await?;
+    let index_dir = root_dir.join("indexes");
+    if !index_dir.exists() {
+        smol::fs::create_dir(&index_dir).await?;
+    }
+    // Child tags like "trips/alaska" go in sub directories
+    let tag_path = index_dir.join(format!("{}.json", tag));
+    if let Some(parent) = tag_path.parent() {
+        smol::fs::create_dir_all(parent).await?;
     }
 
-    let mut tag_path = root_dir.join("indexes");
-    tag_path.push(format!("{}.json", tag));
-
-    // Read the index if it exists, otherwise create it
-    let mut index = if tag_path.exists() {
-        let mut file = File::open(&tag_path).await?;
-        let mut raw_json = vec![];
-        file.read_to_end(&mut raw_json).await?;
-        serde_json::from_slice(&raw_json)?
-    } else {
-        Index {
-            name: tag,
-            topics: HashSet::new(),
+    match index {
+        Some(index) => {
+            // Write to a uniquely named temp file and then rename
+            let temp_tag_path = index_dir.join(format!("{}.tmp", rand_string()));
+            let mut file = File::create(&temp_tag_path).await?;
+            file.write_all(serde_json::to_string(index)?.as_bytes()).await?;
+            file.flush().await?;
+            smol::fs::rename(temp_tag_path, &tag_path).await?;
         }
-    };
-    index.topics.insert(topic.clone());
-
-    // Write to a temporary {topic}.temp.json and then rename
-    let temp_tag_path = tag_path.with_extension(format!("{}.temp.json", topic));
-    let mut file = File::create(&temp_tag_path).await?;
-    file.write_all(serde_json::to_string(&index)?.as_bytes()).await?;
-    std::fs::rename(temp_tag_path, &tag_path.with_extension("json"))?;
-
-    Ok(())
-}
-
-pub async fn rm_tag_for_topic(
-    root_dir: &PathBuf,
-    topic: String,
-    tag: String,
-) -> Result<()> {
-    let index_paths = get_index_paths(root_dir).await?;
-
-    let mut tag_path = root_dir.join("indexes");
-    tag_path.push(format!("{}.json", tag));
-
-    // Read the index if it exists, otherwise fail
-    let mut index: Index = if tag_path.exists() {
-        let mut file = File::open(&tag_path).await?;
-        let mut raw_json = vec![];
-        file.read_to_end(&mut raw_json).await?;
-        serde_json::from_slice(&raw_json)?
-    } else {
-        return Err(anyhow::anyhow!("Tag does not exist"));
-    };
-
-    index.topics.remove(&topic);
-
-    // If topics is empty remove the index file
-    if index.topics.is_empty() {
-        smol::fs::remove_file(&tag_path).await?;
-    } else {
-        // Write to a temporary {topic}.temp.json and then rename
-        let temp_tag_path = tag_path.with_extension(format!("{}.temp.json", topic));
-        let mut file = File::create(&temp_tag_path).await?;
-        file.write_all(serde_json::to_string(&index)?.as_bytes()).await?;
-        std::fs::rename(temp_tag_path, &tag_path.with_extension("json"))?;
+        None if tag_path.exists() => smol::fs::remove_file(&tag_path).await?,
+        None => {}
     }
 
     Ok(())
@@ -381,24 +448,39 @@ pub async fn save_file(
     mut payload: actix_multipart::Field,
     ext: String,
     thumbnail_sender: smol::channel::Sender<PathBuf>,
-) -> Result<String, ServerErr> {
+    limit: u64,
+    too_large: ServerErr,
+) -> Result<(String, u64), ServerErr> {
     let mut hasher = Hasher::new();
-    // First give it a random temp name
-    let rand_name = format!("{}.tmp", rand_string());
+    // First give it a random temp name, in the root dir so the free space
+    // checked is the disk it lands on and the rename doesn't cross filesystems
+    let rand_name = root_dir.join(format!("{}.tmp", rand_string()));
     let file = File::create(&rand_name).await?;
-    log::info!("Saving file to {}", rand_name);
-
-    // TODO limit chunk size
-    let mut buf_writer = BufWriter::new(file);
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk
-            .map_err(|e| anyhow::anyhow!("Error reading payload: {}", e))?;
-        hasher.update(&chunk);
-        buf_writer.write_all(&chunk).await?;
-    }
+    log::info!("Saving file to {}", rand_name.display());
+
+    // Stop as soon as the file goes over the limit instead of filling the disk
+    let mut size = 0u64;
+    let written: Result<(), ServerErr> = async {
+        let mut buf_writer = BufWriter::new(file);
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk
+                .map_err(|e| anyhow::anyhow!("Error reading payload: {}", e))?;
+            size += chunk.len() as u64;
+            if size > limit {
+                return Err(too_large);
+            }
+            hasher.update(&chunk);
+            buf_writer.write_all(&chunk).await?;
+        }
 
-    log::info!("Flushing file {}", rand_name);
-    buf_writer.flush().await?;
+        log::info!("Flushing file {}", rand_name.display());
+        buf_writer.flush().await?;
+        Ok(())
+    }.await;
+    if let Err(e) = written {
+        smol::fs::remove_file(&rand_name).await?;
+        return Err(e);
+    }
 
     let mut hash_output = [0; 32];
     hasher.finalize_xof().fill(&mut hash_output);
@@ -409,7 +491,8 @@ pub async fn save_file(
     let image_fname = format!("{}.{}", uid, ext);
     let image_path = root_dir.join(&image_fname);
     if image_path.exists() {
-        return Ok(image_fname);
+        smol::fs::remove_file(&rand_name).await?;
+        return Ok((image_fname, size));
         //return Err(ServerErr::CustomError(anyhow!("File already exists".to_string())));
     }
 
@@ -420,17 +503,17 @@ pub async fn save_file(
     thumbnail_sender.send(image_path.clone()).await
         .map_err(|e| ServerErr::CustomError(anyhow!("Error sending thumbnail on channel: {}", e)))?;
 
-    Ok(image_fname)
+    Ok((image_fname, size))
 }
 
 
 pub fn mime_and_ext(
     field: &actix_multipart::Field,
     //req: &mut actix_web::HttpRequest,
-) -> Result<(Mime, String), actix_web::error::Error> {
+) -> Result<(Mime, String), ServerErr> {
     let mime = field
         .content_type()
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("No content type"))?;
+        .ok_or_else(|| ServerErr::bad_request("No content type"))?;
     /*
     let mime 
     */
@@ -438,9 +521,9 @@ pub fn mime_and_ext(
     Ok((mime.clone(), ext))
 }
 
-pub fn is_valid_media(mime: &Mime) -> Result<(), actix_web::error::Error> {
+pub fn is_valid_media(mime: &Mime) -> Result<(), ServerErr> {
     if mime.type_() != "image" && mime.type_() != "video" {
-        return Err(actix_web::error::ErrorBadRequest(format!(
+        return Err(ServerErr::FiletypeError(format!(
             "Invalid content type {}",
             mime
         )));
@@ -9,6 +9,43 @@ export const img_server: string = "http://127.0.0.1:2342";
 interface Index {
     name: string;
     topics: string[];
+    preview: string[];
+    media: TaggedMedia[];
+}
+
+interface TopicAddress {
+    owner: string;
+    topic: string;
+}
+
+interface TaggedMedia {
+    topic: string;
+    uid: string;
+}
+
+interface IndexSummary {
+    name: string;
+    topics: number;
+}
+
+interface MediaEntry {
+    uid: string;
+    caption: string | null;
+}
+
+interface TopicListing {
+    title: string | null;
+    description: string | null;
+    cover: string | null;
+    media: MediaEntry[];
+}
+
+// Challenges are signed along with the server and what the signature is for,
+// so it can't be replayed against another server or for another purpose
+function challenge_message(purpose: string, challenge: Uint8Array): Uint8Array {
+    const encoded = Buffer.from(challenge).toString('base64');
+    const msg = `img challenge\norigin: ${img_server}\npurpose: ${purpose}\nchallenge: ${encoded}`;
+    return new TextEncoder().encode(msg);
 }
 
 export async function authenticate(challenge: Uint8Array): Promise<void> { 
@@ -17,7 +54,7 @@ export async function authenticate(challenge: Uint8Array): Promise<void> {
     // Convert private key to Uint8Array
     //let keypair = sign.keyPair.fromSecretKey(decoded);
     const pubKey = await ed.getPublicKeyAsync(decoded);
-    const sig = await ed.signAsync(challenge, decoded);
+    const sig = await ed.signAsync(challenge_message('authenticate', challenge), decoded);
     // pubkey to base64 string
     const pubKeyStr = Buffer.from(pubKey).toString('base64');
     /*
@@ -57,16 +94,20 @@ export async function get_challenge(): Promise<Uint8Array> {
     return decoded;
 }
 
-export async function generate_key(): Promise<Uint8Array> {
-    let response = await fetch(`${img_server}/generate-key`);
-    throw_err(response, "Error generating key");
-    return response.text();
+// The private key never leaves the browser, only its public key is sent
+export async function generate_key(): Promise<string> {
+    const secret = ed.utils.randomPrivateKey();
+    return Buffer.from(secret).toString('base64');
 }
 
 export async function rm_tag(topic: string, tag: string): Promise<string> {
     let response = await fetch(`${img_server}/${topic}/remove-tag`, {
         method: 'POST',
-        body: `"${tag}"`,
+        credentials: 'include',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(tag),
     });
     throw_err(response, "Error removing tag");
 
@@ -76,7 +117,11 @@ export async function rm_tag(topic: string, tag: string): Promise<string> {
 export async function add_tag(topic: string, tag: string): Promise<string> {
     let response = await fetch(`${img_server}/${topic}/new-tag`, {
         method: 'POST',
-        body: `"${tag}"`,
+        credentials: 'include',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(tag),
     });
     throw_err(response, "Error adding tag");
     
@@ -90,9 +135,22 @@ export async function get_tags(topic: string): Promise<string[]> {
     return await response.json() || [];
 }
 
+// Owner of the topic a bare name refers to, null if there is no single one
+export async function resolve_topic(topic: string): Promise<TopicAddress | null> {
+    const response = await fetch(`${img_server}/resolve/${topic}`, {
+        credentials: 'include',
+    });
+    if (response.status != 200) {
+        return null;
+    }
+
+    return await response.json();
+}
+
 export async function get_image_names(topic: string): Promise<string[]> {
   try {
-    const pubkey = encodeURIComponent(await get_pubkey());
+    const address = await resolve_topic(topic);
+    const pubkey = encodeURIComponent(address ? address.owner : await get_pubkey());
     //const pkid = await pubkey_id(pubkey);
 
     // Make a GET request to the endpoint
@@ -102,8 +160,8 @@ export async function get_image_names(topic: string): Promise<string[]> {
 
     // If the request was successful, parse the response as JSON
     if (response.ok) {
-      const data: string[] = await response.json();
-      return data;
+      const data: TopicListing = await response.json();
+      return data.media.map((m) => m.uid);
     } else {
       const text = await response.text();
       console.error('Error:', response.status, text);
@@ -179,7 +237,7 @@ async function processFile(topic: string, file) {
 }
 */
 
-export async function get_all_indexes(): Promise<list[string]> {
+export async function get_all_indexes(): Promise<IndexSummary[]> {
     const response = await fetch(`${img_server}/all-indexes`);
     throw_err(response, "Error getting indexes");
 
@@ -201,6 +259,10 @@ export async function create_index(
     // Make a POST request to the img server /new-index with a json body
     const response = await fetch(`${img_server}/new-index`, {
         method: 'POST',
+        credentials: 'include',
+        headers: {
+            'Content-Type': 'application/json'
+        },
         body: JSON.stringify({
             name: index,
             topics: topics